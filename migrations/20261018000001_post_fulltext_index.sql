@@ -0,0 +1,3 @@
+-- 日本語の投稿が多いので、空白区切りではなく ngram で分割する
+ALTER TABLE blogapp_post
+  ADD FULLTEXT INDEX blogapp_post_fulltext (title, contents) WITH PARSER ngram;
@@ -0,0 +1,18 @@
+-- カテゴリとは別に、投稿に複数付けられるタグ
+CREATE TABLE blogapp_tag (
+  id INT NOT NULL AUTO_INCREMENT,
+  name VARCHAR(100) NOT NULL,
+  PRIMARY KEY (id),
+  UNIQUE KEY blogapp_tag_name (name)
+) DEFAULT CHARSET=utf8mb4;
+
+CREATE TABLE blogapp_post_tags (
+  id INT NOT NULL AUTO_INCREMENT,
+  post_id INT NOT NULL,
+  tag_id INT NOT NULL,
+  PRIMARY KEY (id),
+  UNIQUE KEY blogapp_post_tags_post_id_tag_id (post_id, tag_id),
+  KEY blogapp_post_tags_tag_id (tag_id),
+  CONSTRAINT blogapp_post_tags_post_id FOREIGN KEY (post_id) REFERENCES blogapp_post (id) ON DELETE CASCADE,
+  CONSTRAINT blogapp_post_tags_tag_id FOREIGN KEY (tag_id) REFERENCES blogapp_tag (id) ON DELETE CASCADE
+) DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,6 @@
+-- 投稿の更新日時。Django admin からの更新でも自動で更新される
+ALTER TABLE blogapp_post
+  ADD COLUMN updated_at DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6) ON UPDATE CURRENT_TIMESTAMP(6);
+
+-- 既存の投稿は公開日時を更新日時とみなす
+UPDATE blogapp_post SET updated_at = pub_date;
@@ -0,0 +1,208 @@
+use axum::{
+  http::{header, Request, StatusCode},
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+use chrono::{DateTime, TimeZone, Utc};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::sync::Arc;
+
+use async_graphql::{
+  Context,
+  ErrorExtensions,
+  Guard,
+  Result,
+  SimpleObject,
+};
+
+use crate::config::AuthConfig;
+use crate::resolvers::BlogError;
+
+// この role を持つ viewer だけが mutation を実行できる
+const ADMIN_ROLE: &str = "admin";
+
+#[derive(Deserialize)]
+struct Claims {
+  sub: String,
+  exp: i64,
+  #[serde(default)]
+  name: Option<String>,
+  #[serde(default)]
+  roles: Vec<String>,
+}
+
+// リクエストしてきたユーザー。トークンがない場合は匿名
+#[derive(Clone, SimpleObject)]
+pub struct Viewer {
+  /// sub claim of the token
+  id: Option<String>,
+  name: Option<String>,
+  roles: Vec<String>,
+  authenticated: bool,
+  expires_at: Option<DateTime<Utc>>,
+}
+
+impl Viewer {
+  pub fn anonymous() -> Self {
+    Viewer {
+      id: None,
+      name: None,
+      roles: Vec::new(),
+      authenticated: false,
+      expires_at: None,
+    }
+  }
+
+  pub fn is_authenticated(&self) -> bool {
+    self.authenticated
+  }
+
+  pub fn is_admin(&self) -> bool {
+    self.authenticated && self.roles.iter().any(|role| role == ADMIN_ROLE)
+  }
+}
+
+// 以前の ADMIN_TOKEN。JWT の代わりに Bearer で送られた場合は admin として扱う
+pub struct AdminToken(String);
+
+impl AdminToken {
+  pub fn from_config(config: &AuthConfig) -> Option<Self> {
+    config.admin_token.clone().map(AdminToken)
+  }
+
+  fn matches(&self, token: &str) -> bool {
+    constant_time_eq(self.0.as_bytes(), token.as_bytes())
+  }
+
+  fn viewer(&self) -> Viewer {
+    Viewer {
+      id: Some("admin-token".to_string()),
+      name: None,
+      roles: vec![ADMIN_ROLE.to_string()],
+      authenticated: true,
+      expires_at: None,
+    }
+  }
+}
+
+// 比較にかかる時間からトークンを推測されないようにする
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  if a.len() != b.len() {
+    return false;
+  }
+  a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Authorization: Bearer の JWT を検証する
+pub struct JwtVerifier {
+  key: DecodingKey,
+  validation: Validation,
+}
+
+impl JwtVerifier {
+  // jwt_algorithm が未設定の場合は None (全てのリクエストが匿名になる)
+  // 必須項目の有無は Config::validate で確認済み
+  pub fn from_config(config: &AuthConfig) -> std::result::Result<Option<Self>, String> {
+    let required = |value: &Option<String>, key: &str| value.clone().ok_or_else(|| format!("{} is not set", key));
+
+    let (algorithm, key) = match config.jwt_algorithm.as_deref() {
+      None => return Ok(None),
+      Some("HS256") => (
+        Algorithm::HS256,
+        DecodingKey::from_secret(required(&config.jwt_secret, "auth.jwt_secret")?.as_bytes()),
+      ),
+      Some("RS256") => (
+        Algorithm::RS256,
+        DecodingKey::from_rsa_pem(required(&config.jwt_public_key, "auth.jwt_public_key")?.as_bytes())
+          .map_err(|e| format!("auth.jwt_public_key: {}", e))?,
+      ),
+      Some(other) => return Err(format!("auth.jwt_algorithm must be HS256 or RS256, got {}", other)),
+    };
+
+    // exp は常に検証する。iss / aud は設定されている場合だけ検証する
+    let mut validation = Validation::new(algorithm);
+    if let Some(issuer) = &config.jwt_issuer {
+      validation.set_issuer(&[issuer]);
+    }
+    if let Some(audience) = &config.jwt_audience {
+      validation.set_audience(&[audience]);
+    }
+    validation.leeway = config.jwt_leeway;
+
+    Ok(Some(JwtVerifier { key, validation }))
+  }
+
+  fn verify(&self, token: &str) -> std::result::Result<Viewer, jsonwebtoken::errors::Error> {
+    let claims = decode::<Claims>(token, &self.key, &self.validation)?.claims;
+    Ok(Viewer {
+      id: Some(claims.sub),
+      name: claims.name,
+      roles: claims.roles,
+      authenticated: true,
+      expires_at: Utc.timestamp_opt(claims.exp, 0).single(),
+    })
+  }
+}
+
+fn bearer_token<B>(req: &Request<B>) -> Option<String> {
+  let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+  let token = value.strip_prefix("Bearer ")?.trim();
+  match token.is_empty() {
+    true => None,
+    false => Some(token.to_string()),
+  }
+}
+
+fn unauthorized() -> Response {
+  (
+    StatusCode::UNAUTHORIZED,
+    [(header::WWW_AUTHENTICATE, "Bearer error=\"invalid_token\"")],
+    "unauthorized",
+  )
+    .into_response()
+}
+
+/**
+ * middleware
+ */
+// トークンがなければ匿名の Viewer、正しければその Viewer をリクエストに載せる
+// JWT の検証に失敗した場合は 401 を返す (GraphQL のルートにだけ掛ける)
+// JWT が未設定の場合、ADMIN_TOKEN 以外のトークンは検証できないので匿名として扱う
+pub async fn authenticate<B>(mut req: Request<B>, next: Next<B>) -> Response {
+  let verifier = req.extensions().get::<Arc<JwtVerifier>>().cloned();
+  let admin_token = req.extensions().get::<Arc<AdminToken>>().cloned();
+  let viewer = match (bearer_token(&req), verifier, admin_token) {
+    (None, _, _) => Viewer::anonymous(),
+    (Some(token), _, Some(admin_token)) if admin_token.matches(&token) => admin_token.viewer(),
+    (Some(token), Some(verifier), _) => match verifier.verify(&token) {
+      Ok(viewer) => viewer,
+      Err(_) => return unauthorized(),
+    },
+    (Some(_), None, _) => Viewer::anonymous(),
+  };
+  req.extensions_mut().insert(viewer);
+  next.run(req).await
+}
+
+pub fn is_admin(ctx: &Context<'_>) -> bool {
+  match ctx.data_opt::<Viewer>() {
+    Some(viewer) => viewer.is_admin(),
+    None => false,
+  }
+}
+
+/**
+ * guards
+ */
+pub struct AdminGuard;
+
+#[async_trait::async_trait]
+impl Guard for AdminGuard {
+  async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+    match is_admin(ctx) {
+      true => Ok(()),
+      false => Err(BlogError::Unauthorized.extend()),
+    }
+  }
+}
@@ -0,0 +1,265 @@
+use lru::LruCache;
+use std::{
+  any::Any,
+  collections::HashMap,
+  future::Future,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+    Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+use crate::config::CacheConfig;
+use crate::metrics;
+use crate::resolvers::BlogError;
+
+// 一件取得のキーの接頭辞。これ以外のキー (一覧や件数) は投稿が変わるたびに全て捨てる
+const POST_PREFIX: &str = "post:";
+
+struct Entry {
+  value: Arc<dyn Any + Send + Sync>,
+  expires_at: Instant,
+}
+
+// 取得中のキーごとのロック。中身は取得に失敗した場合のエラー
+type Flight = Arc<tokio::sync::Mutex<Option<BlogError>>>;
+
+// in_flight に登録したロックを、取得を終えたかキャンセルされた (future が捨てられた) 時点で片付ける
+struct InFlight<'a> {
+  cache: &'a ResolverCache,
+  key: &'a str,
+  lock: Flight,
+}
+
+impl<'a> InFlight<'a> {
+  fn join(cache: &'a ResolverCache, key: &'a str) -> Self {
+    let lock = cache.in_flight.lock().unwrap().entry(key.to_string()).or_default().clone();
+    InFlight { cache, key, lock }
+  }
+}
+
+impl Drop for InFlight<'_> {
+  fn drop(&mut self) {
+    // 待っているリクエストがいなければ片付ける (map と自分の分で 2)
+    let mut in_flight = match self.cache.in_flight.lock() {
+      Ok(in_flight) => in_flight,
+      Err(_) => return,
+    };
+    if Arc::strong_count(&self.lock) <= 2 {
+      in_flight.remove(self.key);
+    }
+  }
+}
+
+// resolver の結果を TTL 付きの LRU でキャッシュする
+// 同じキーの取得が同時に来た場合は、最初の一つだけが DB を読み、残りはその結果 (エラーも含む) を待つ
+pub struct ResolverCache {
+  enabled: bool,
+  ttl: Duration,
+  entries: Mutex<LruCache<String, Entry>>,
+  in_flight: Mutex<HashMap<String, Flight>>,
+  // 取得中に無効化された結果を保存しないための世代番号
+  generation: AtomicU64,
+}
+
+impl ResolverCache {
+  pub fn new(config: &CacheConfig) -> Self {
+    ResolverCache {
+      enabled: config.enabled,
+      ttl: Duration::from_secs(config.ttl),
+      entries: Mutex::new(LruCache::new(config.capacity)),
+      in_flight: Mutex::new(HashMap::new()),
+      generation: AtomicU64::new(0),
+    }
+  }
+
+  pub fn post_key(id: i32) -> String {
+    format!("{}{}", POST_PREFIX, id)
+  }
+
+  pub fn entry_count(&self) -> usize {
+    self.entries.lock().unwrap().len()
+  }
+
+  fn get<T: Clone + 'static>(&self, key: &str) -> Option<T> {
+    let mut entries = self.entries.lock().unwrap();
+    let expired = match entries.get(key) {
+      Some(entry) if entry.expires_at > Instant::now() => return entry.value.downcast_ref::<T>().cloned(),
+      Some(_) => true,
+      None => false,
+    };
+    if expired {
+      entries.pop(key);
+    }
+    None
+  }
+
+  pub async fn get_or_fetch<T, F, Fut>(&self, key: String, fetch: F) -> Result<T, BlogError>
+  where
+    T: Clone + Send + Sync + 'static,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, BlogError>>,
+  {
+    if !self.enabled {
+      return fetch().await;
+    }
+    if let Some(value) = self.get::<T>(&key) {
+      metrics::record_cache(true);
+      return Ok(value);
+    }
+
+    // 同じキーを取得中のリクエストがあれば、それが終わるのを待ってからもう一度見る
+    let flight = InFlight::join(self, &key);
+    let mut failed = flight.lock.lock().await;
+    if let Some(value) = self.get::<T>(&key) {
+      metrics::record_cache(true);
+      return Ok(value);
+    }
+    // 先に取得したリクエストが失敗していれば、DB が落ちている間に順番に取り直さないよう同じエラーを返す
+    if let Some(err) = &*failed {
+      return Err(err.clone());
+    }
+
+    metrics::record_cache(false);
+    let generation = self.generation.load(Ordering::SeqCst);
+    let result = fetch().await;
+    match &result {
+      Ok(value) => {
+        let mut entries = self.entries.lock().unwrap();
+        if generation == self.generation.load(Ordering::SeqCst) {
+          let entry = Entry {
+            value: Arc::new(value.clone()),
+            expires_at: Instant::now() + self.ttl,
+          };
+          entries.put(key.clone(), entry);
+        }
+      },
+      // エラーはキャッシュせず、今待っているリクエストにだけ返す
+      Err(err) => *failed = Some(err.clone()),
+    }
+    result
+  }
+
+  // 投稿が変わった場合は、その投稿と一覧・件数などを捨てる
+  pub fn invalidate_post(&self, id: i32) {
+    let mut entries = self.entries.lock().unwrap();
+    self.generation.fetch_add(1, Ordering::SeqCst);
+    let post_key = ResolverCache::post_key(id);
+    let keys: Vec<String> = entries
+      .iter()
+      .map(|(key, _)| key)
+      .filter(|key| **key == post_key || !key.starts_with(POST_PREFIX))
+      .cloned()
+      .collect();
+    for key in keys {
+      entries.pop(&key);
+    }
+  }
+
+  // カテゴリなど、API の外で変わったデータに合わせて全て捨てる
+  pub fn invalidate_all(&self) {
+    let mut entries = self.entries.lock().unwrap();
+    self.generation.fetch_add(1, Ordering::SeqCst);
+    entries.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures_util::future::join_all;
+  use std::sync::atomic::AtomicUsize;
+
+  fn cache() -> ResolverCache {
+    ResolverCache::new(&CacheConfig::default())
+  }
+
+  #[tokio::test]
+  async fn caches_values() {
+    let cache = cache();
+    let fetched = AtomicUsize::new(0);
+    for _ in 0..3 {
+      let value = cache
+        .get_or_fetch("key".to_string(), || async {
+          fetched.fetch_add(1, Ordering::SeqCst);
+          Ok(1)
+        })
+        .await;
+      assert_eq!(value.unwrap(), 1);
+    }
+    assert_eq!(fetched.load(Ordering::SeqCst), 1);
+  }
+
+  #[tokio::test]
+  async fn invalidation_drops_values() {
+    let cache = cache();
+    cache.get_or_fetch("posts".to_string(), || async { Ok(1) }).await.unwrap();
+    cache.get_or_fetch(ResolverCache::post_key(2), || async { Ok(2) }).await.unwrap();
+    cache.invalidate_post(1);
+    assert_eq!(cache.get::<i32>("posts"), None);
+    assert_eq!(cache.get::<i32>(&ResolverCache::post_key(2)), Some(2));
+  }
+
+  #[tokio::test]
+  async fn concurrent_requests_share_one_fetch() {
+    let cache = cache();
+    let fetched = AtomicUsize::new(0);
+    let requests = (0..5).map(|_| {
+      cache.get_or_fetch("key".to_string(), || async {
+        fetched.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        Ok(1)
+      })
+    });
+    let results = join_all(requests).await;
+    assert!(results.iter().all(|result| matches!(result, Ok(1))));
+    assert_eq!(fetched.load(Ordering::SeqCst), 1);
+    assert!(cache.in_flight.lock().unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn errors_are_shared_with_waiters_but_not_cached() {
+    let cache = cache();
+    let fetched = AtomicUsize::new(0);
+    let requests = (0..5).map(|_| {
+      cache.get_or_fetch("key".to_string(), || async {
+        fetched.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        Err::<i32, _>(BlogError::ServerError("down".to_string()))
+      })
+    });
+    let results = join_all(requests).await;
+    assert!(results.iter().all(|result| result.is_err()));
+    assert_eq!(fetched.load(Ordering::SeqCst), 1);
+    assert!(cache.in_flight.lock().unwrap().is_empty());
+
+    // 後から来たリクエストは取り直す
+    let value = cache.get_or_fetch("key".to_string(), || async { Ok(1) }).await;
+    assert_eq!(value.unwrap(), 1);
+  }
+
+  #[tokio::test]
+  async fn cancelled_fetch_releases_in_flight() {
+    let cache = cache();
+    let pending = cache.get_or_fetch("key".to_string(), futures_util::future::pending::<Result<i32, BlogError>>);
+    assert!(tokio::time::timeout(Duration::from_millis(10), pending).await.is_err());
+    assert!(cache.in_flight.lock().unwrap().is_empty());
+
+    let value = cache.get_or_fetch("key".to_string(), || async { Ok(1) }).await;
+    assert_eq!(value.unwrap(), 1);
+  }
+
+  #[tokio::test]
+  async fn waiter_takes_over_cancelled_fetch() {
+    let cache = cache();
+    let leader = cache.get_or_fetch("key".to_string(), futures_util::future::pending::<Result<i32, BlogError>>);
+    let waiter = cache.get_or_fetch("key".to_string(), || async { Ok(2) });
+    let leader = tokio::time::timeout(Duration::from_millis(10), leader);
+    let (leader, waiter) = tokio::join!(leader, waiter);
+    assert!(leader.is_err());
+    assert_eq!(waiter.unwrap(), 2);
+    assert!(cache.in_flight.lock().unwrap().is_empty());
+  }
+}
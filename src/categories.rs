@@ -0,0 +1,129 @@
+use chrono::{DateTime, Utc};
+use sqlx::mysql::MySqlPool;
+use tracing::instrument;
+
+use async_graphql::{
+  ComplexObject,
+  Context,
+  FieldResult,
+  SimpleObject,
+};
+
+use crate::filter::PostFilter;
+use crate::limits;
+use crate::pagination::{self, PostConnection};
+use crate::resolvers::BlogError;
+
+#[derive(Clone, SimpleObject)]
+#[derive(sqlx::FromRow)]
+#[graphql(complex)]
+pub struct Category {
+  pub(crate) id: i32,
+  pub(crate) name: String,
+  /// number of published posts
+  pub(crate) post_count: i64,
+  /// pub_date of the latest published post
+  pub(crate) latest_pub_date: Option<DateTime<Utc>>,
+}
+
+#[ComplexObject]
+impl Category {
+  // カテゴリ内の公開済み投稿を Relay 形式で返す
+  #[graphql(complexity = "limits::list_size(first, last) * child_complexity")]
+  async fn posts(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(desc = "number of posts from the start")] first: Option<i32>,
+    #[graphql(desc = "cursor to start after")] after: Option<String>,
+    #[graphql(desc = "number of posts from the end")] last: Option<i32>,
+    #[graphql(desc = "cursor to end before")] before: Option<String>,
+  ) -> FieldResult<PostConnection> {
+    pagination::posts(ctx, after, before, first, last, PostFilter::category(self.name.clone())).await
+  }
+}
+
+/**
+ * database
+ */
+// 公開済みの投稿だけを数えるため、open の条件は WHERE ではなく JOIN に書く
+const SELECT_CATEGORIES: &str = "
+  SELECT
+    blogapp_category.id as id,
+    blogapp_category.name as name,
+    count(blogapp_post.id) as post_count,
+    max(blogapp_post.pub_date) as latest_pub_date
+  FROM
+    blogapp_category
+  LEFT JOIN
+    blogapp_post
+  ON
+    blogapp_post.category_id = blogapp_category.id
+    AND blogapp_post.open = true
+";
+
+// get all categories
+#[instrument(skip_all)]
+pub async fn get_categories(pool: &MySqlPool) -> Result<Vec<Category>, BlogError> {
+  let sql = format!(
+    "{}
+    GROUP BY blogapp_category.id, blogapp_category.name
+    ORDER BY blogapp_category.name
+    ",
+    SELECT_CATEGORIES
+  );
+
+  let categories = sqlx::query_as::<_, Category>(sql.as_str())
+    .fetch_all(pool)
+    .await;
+
+  match categories {
+    Ok(categories) => Ok(categories),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+// get categories by ids
+#[instrument(skip(pool))]
+pub async fn get_categories_by_ids(pool: &MySqlPool, ids: &[i32]) -> Result<Vec<Category>, BlogError> {
+  let sql = format!(
+    "{}
+    WHERE blogapp_category.id IN ({})
+    GROUP BY blogapp_category.id, blogapp_category.name
+    ",
+    SELECT_CATEGORIES,
+    vec!["?"; ids.len()].join(", ")
+  );
+
+  let mut query = sqlx::query_as::<_, Category>(sql.as_str());
+  for id in ids {
+    query = query.bind(id);
+  }
+
+  match query.fetch_all(pool).await {
+    Ok(categories) => Ok(categories),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+// get category by name
+#[instrument(skip_all)]
+pub async fn get_category(pool: &MySqlPool, name: &str) -> Result<Category, BlogError> {
+  let sql = format!(
+    "{}
+    WHERE blogapp_category.name = ?
+    GROUP BY blogapp_category.id, blogapp_category.name
+    ",
+    SELECT_CATEGORIES
+  );
+
+  let category = sqlx::query_as::<_, Category>(sql.as_str())
+    .bind(name)
+    .fetch_one(pool)
+    .await;
+
+  match category {
+    Ok(category) => Ok(category),
+    Err(sqlx::Error::RowNotFound) => Err(BlogError::NotFoundCategory),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
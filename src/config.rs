@@ -0,0 +1,877 @@
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+use std::{env, fmt::Display, fs, net::IpAddr, path::Path, str::FromStr};
+
+use crate::rate_limit::TrustedProxy;
+
+// 設定ファイルが指定されなかった場合に読みにいくパス(存在しなければ無視する)
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+  #[error("failed to read {path}: {reason}")]
+  Read { path: String, reason: String },
+
+  #[error("failed to parse {path}: {reason}")]
+  Parse { path: String, reason: String },
+
+  #[error("invalid value for {key}: {reason}")]
+  Env { key: String, reason: String },
+
+  #[error("invalid config: {0}")]
+  Invalid(String),
+}
+
+/**
+ * config
+ */
+// 設定は TOML ファイル -> 環境変数の順に上書きして作る
+// 秘密情報は `DATABASE_URL_FILE` のように `_FILE` を付けるとファイルから読み込める
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub server: ServerConfig,
+  pub database: DatabaseConfig,
+  pub posts: PostsConfig,
+  pub site: SiteConfig,
+  pub auth: AuthConfig,
+  pub preview: PreviewConfig,
+  pub log: LogConfig,
+  pub limits: LimitsConfig,
+  pub persisted_queries: PersistedQueriesConfig,
+  pub cache: CacheConfig,
+  pub rate_limit: RateLimitConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+  pub host: String,
+  pub port: u16,
+  // "*" を含む場合は全てのオリジンを許可する
+  pub cors_origins: Vec<String>,
+  // シグナルを受け取ってから新規接続の受付を止めるまでの待ち時間(秒)
+  // この間に /readyz が 503 を返すので、ロードバランサから外れるのを待つ
+  pub shutdown_delay: u64,
+  // 処理中のリクエストの完了を待つ最大時間(秒)
+  pub drain_timeout: u64,
+}
+
+impl Default for ServerConfig {
+  fn default() -> Self {
+    ServerConfig {
+      host: "0.0.0.0".to_string(),
+      port: 8000,
+      // 一旦現段階で想定してるのはブログだけ
+      cors_origins: vec!["*".to_string()],
+      shutdown_delay: 0,
+      drain_timeout: 30,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+  pub url: String,
+  pub max_connections: u32,
+  pub min_connections: u32,
+  // 秒
+  pub acquire_timeout: u64,
+  pub idle_timeout: u64,
+  // これより時間のかかった SQL を warn で出す(ミリ秒)
+  pub slow_query_threshold: u64,
+}
+
+impl Default for DatabaseConfig {
+  fn default() -> Self {
+    DatabaseConfig {
+      url: "".to_string(),
+      max_connections: 10,
+      min_connections: 1,
+      acquire_timeout: 30,
+      idle_timeout: 600,
+      slow_query_threshold: 1000,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostsConfig {
+  // 1ページあたりの投稿数
+  pub page_size: i32,
+  // 一覧で返す contents の文字数
+  pub excerpt_length: i32,
+}
+
+impl Default for PostsConfig {
+  fn default() -> Self {
+    PostsConfig {
+      page_size: 5,
+      excerpt_length: 200,
+    }
+  }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedContent {
+  Full,
+  Excerpt,
+}
+
+impl FromStr for FeedContent {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "full" => Ok(FeedContent::Full),
+      "excerpt" => Ok(FeedContent::Excerpt),
+      _ => Err("must be full or excerpt".to_string()),
+    }
+  }
+}
+
+// フィードやサイトマップに載せるサイトの情報
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SiteConfig {
+  pub url: String,
+  // この API サーバ自身の URL。フィードの self リンクやサイトマップのインデックスに使う
+  pub api_url: String,
+  pub title: String,
+  pub description: String,
+  pub feed_size: i32,
+  pub feed_content: FeedContent,
+  // sitemap を公開する URL。未設定の場合は url (サイト側で /sitemap.xml と /sitemaps/ をこの API にプロキシする)
+  // 別ホストの sitemap は、url の robots.txt に `Sitemap:` で書かれていないと検索エンジンに無視される
+  pub sitemap_url: Option<String>,
+}
+
+impl Default for SiteConfig {
+  fn default() -> Self {
+    SiteConfig {
+      url: "https://takurinton.dev".to_string(),
+      api_url: "https://api.takurinton.dev".to_string(),
+      title: "takurinton".to_string(),
+      description: "takurinton のブログ".to_string(),
+      feed_size: 20,
+      feed_content: FeedContent::Full,
+      sitemap_url: None,
+    }
+  }
+}
+
+impl SiteConfig {
+  pub fn post_url(&self, id: i32) -> String {
+    format!("{}/post/{}", self.url, id)
+  }
+
+  pub fn category_url(&self, name: &str) -> String {
+    format!("{}/category/{}", self.url, encode_path_segment(name))
+  }
+
+  pub fn sitemap_url(&self, file: &str) -> String {
+    format!("{}/sitemaps/{}", self.sitemap_url.as_deref().unwrap_or(&self.url), file)
+  }
+}
+
+// URL のパスに日本語のカテゴリ名などを入れるためにエンコードする
+pub fn encode_path_segment(segment: &str) -> String {
+  let mut encoded = String::with_capacity(segment.len());
+  for byte in segment.bytes() {
+    match byte {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+      _ => encoded.push_str(&format!("%{:02X}", byte)),
+    }
+  }
+  encoded
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+  // HS256 or RS256。未設定の場合は JWT を検証しない
+  pub jwt_algorithm: Option<String>,
+  pub jwt_secret: Option<String>,
+  // RS256 の公開鍵 (PEM)
+  pub jwt_public_key: Option<String>,
+  pub jwt_issuer: Option<String>,
+  pub jwt_audience: Option<String>,
+  // 秒
+  pub jwt_leeway: u64,
+  // 以前の固定の管理用トークン。JWT に移行するまでの互換のために残している
+  // このトークンを Bearer で送ると admin として扱う
+  pub admin_token: Option<String>,
+}
+
+impl Default for AuthConfig {
+  fn default() -> Self {
+    AuthConfig {
+      jwt_algorithm: None,
+      jwt_secret: None,
+      jwt_public_key: None,
+      jwt_issuer: None,
+      jwt_audience: None,
+      jwt_leeway: 60,
+      admin_token: None,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PreviewConfig {
+  // 未設定の場合はプレビューが使えない
+  pub secret: Option<String>,
+  // createPreviewToken の expiresIn に指定できる最大値(秒)
+  pub max_expires_in: i64,
+}
+
+impl Default for PreviewConfig {
+  fn default() -> Self {
+    PreviewConfig {
+      secret: None,
+      // 30日
+      max_expires_in: 60 * 60 * 24 * 30,
+    }
+  }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+  Pretty,
+  Json,
+}
+
+impl FromStr for LogFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "pretty" => Ok(LogFormat::Pretty),
+      "json" => Ok(LogFormat::Json),
+      _ => Err("must be pretty or json".to_string()),
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+  // 本番では json にしてログ基盤に流す
+  pub format: LogFormat,
+  // RUST_LOG と同じ書式 (例: "info,sqlx=debug")
+  pub level: String,
+}
+
+impl Default for LogConfig {
+  fn default() -> Self {
+    LogConfig {
+      format: LogFormat::Pretty,
+      level: "info".to_string(),
+    }
+  }
+}
+
+// 公開エンドポイントなので、重いクエリは実行前に弾く
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+  pub max_depth: usize,
+  // 一覧のフィールドは件数倍で数える
+  pub max_complexity: usize,
+  pub max_aliases: usize,
+  pub max_root_fields: usize,
+  // イントロスペクションは複雑度を見ない代わりに、深さだけ別の上限で抑える
+  pub max_introspection_depth: usize,
+}
+
+impl Default for LimitsConfig {
+  fn default() -> Self {
+    LimitsConfig {
+      max_depth: 10,
+      max_complexity: 1000,
+      max_aliases: 30,
+      max_root_fields: 20,
+      max_introspection_depth: 15,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistedQueriesConfig {
+  pub enabled: bool,
+  // 保存しておくクエリの数。溢れたら古いものから捨てる
+  pub capacity: usize,
+}
+
+impl Default for PersistedQueriesConfig {
+  fn default() -> Self {
+    PersistedQueriesConfig {
+      enabled: true,
+      capacity: 1000,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+  pub enabled: bool,
+  // 秒
+  pub ttl: u64,
+  pub capacity: usize,
+}
+
+impl Default for CacheConfig {
+  fn default() -> Self {
+    CacheConfig {
+      enabled: true,
+      ttl: 60,
+      capacity: 1000,
+    }
+  }
+}
+
+// クライアント (IP アドレスか API キー) ごとのトークンバケット
+// query と mutation は別々のバケットで数える
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+  pub enabled: bool,
+  // 1分あたりに補充されるトークン数と、バケットの大きさ (連続して送れる数)
+  pub queries_per_minute: u32,
+  pub query_burst: u32,
+  pub mutations_per_minute: u32,
+  pub mutation_burst: u32,
+  // X-Forwarded-For を信用するプロキシ (IP アドレスか CIDR)。空の場合は接続元の IP アドレスを使う
+  pub trusted_proxies: Vec<String>,
+  // X-Api-Key で送られた場合は IP アドレスではなくキーごとに数える
+  pub api_keys: Vec<String>,
+  // 覚えておくクライアントの数。溢れたら古いものから捨てる
+  pub max_clients: usize,
+}
+
+impl Default for RateLimitConfig {
+  fn default() -> Self {
+    RateLimitConfig {
+      enabled: true,
+      queries_per_minute: 300,
+      query_burst: 60,
+      mutations_per_minute: 30,
+      mutation_burst: 10,
+      trusted_proxies: vec![],
+      api_keys: vec![],
+      max_clients: 10000,
+    }
+  }
+}
+
+/**
+ * loading
+ */
+// `KEY` か `KEY_FILE` のどちらかから値を読む。両方ある場合はエラーにする
+fn env_value(key: &str) -> Result<Option<String>, ConfigError> {
+  let file_key = format!("{}_FILE", key);
+  match (env::var(key).ok(), env::var(&file_key).ok()) {
+    (Some(_), Some(_)) => Err(ConfigError::Env {
+      key: key.to_string(),
+      reason: format!("both {} and {} are set", key, file_key),
+    }),
+    (Some(value), None) => Ok(Some(value)),
+    (None, Some(path)) => match fs::read_to_string(&path) {
+      Ok(value) => Ok(Some(value.trim_end().to_string())),
+      Err(e) => Err(ConfigError::Read { path, reason: e.to_string() }),
+    },
+    (None, None) => Ok(None),
+  }
+}
+
+// カンマ区切りの環境変数を読む。空の要素は捨てる
+fn split_list(value: &str) -> Vec<String> {
+  value
+    .split(',')
+    .map(|item| item.trim().to_string())
+    .filter(|item| !item.is_empty())
+    .collect()
+}
+
+fn override_with<T>(target: &mut T, key: &str) -> Result<(), ConfigError>
+where
+  T: FromStr,
+  T::Err: Display,
+{
+  if let Some(value) = env_value(key)? {
+    *target = value.parse().map_err(|e: T::Err| ConfigError::Env {
+      key: key.to_string(),
+      reason: e.to_string(),
+    })?;
+  }
+  Ok(())
+}
+
+fn override_opt(target: &mut Option<String>, key: &str) -> Result<(), ConfigError> {
+  if let Some(value) = env_value(key)? {
+    *target = Some(value);
+  }
+  Ok(())
+}
+
+impl Config {
+  pub fn load() -> Result<Self, ConfigError> {
+    let mut config = match env::var("CONFIG_FILE") {
+      Ok(path) => Config::from_file(&path)?,
+      Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => Config::from_file(DEFAULT_CONFIG_FILE)?,
+      Err(_) => Config::default(),
+    };
+    config.override_with_env()?;
+    config.validate()?;
+    Ok(config)
+  }
+
+  fn from_file(path: &str) -> Result<Self, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Read {
+      path: path.to_string(),
+      reason: e.to_string(),
+    })?;
+    toml::from_str(&text).map_err(|e| ConfigError::Parse {
+      path: path.to_string(),
+      reason: e.to_string(),
+    })
+  }
+
+  fn override_with_env(&mut self) -> Result<(), ConfigError> {
+    override_with(&mut self.server.host, "HOST")?;
+    override_with(&mut self.server.port, "PORT")?;
+    if let Some(origins) = env_value("CORS_ORIGINS")? {
+      self.server.cors_origins = origins.split(',').map(|origin| origin.trim().to_string()).collect();
+    }
+    override_with(&mut self.server.shutdown_delay, "SHUTDOWN_DELAY")?;
+    override_with(&mut self.server.drain_timeout, "DRAIN_TIMEOUT")?;
+
+    override_with(&mut self.database.url, "DATABASE_URL")?;
+    override_with(&mut self.database.max_connections, "DATABASE_MAX_CONNECTIONS")?;
+    override_with(&mut self.database.min_connections, "DATABASE_MIN_CONNECTIONS")?;
+    override_with(&mut self.database.acquire_timeout, "DATABASE_ACQUIRE_TIMEOUT")?;
+    override_with(&mut self.database.idle_timeout, "DATABASE_IDLE_TIMEOUT")?;
+    override_with(&mut self.database.slow_query_threshold, "DATABASE_SLOW_QUERY_THRESHOLD")?;
+
+    override_with(&mut self.posts.page_size, "PAGE_SIZE")?;
+    override_with(&mut self.posts.excerpt_length, "EXCERPT_LENGTH")?;
+
+    override_with(&mut self.site.url, "SITE_URL")?;
+    override_with(&mut self.site.api_url, "API_URL")?;
+    override_with(&mut self.site.title, "SITE_TITLE")?;
+    override_with(&mut self.site.description, "SITE_DESCRIPTION")?;
+    override_with(&mut self.site.feed_size, "FEED_SIZE")?;
+    override_with(&mut self.site.feed_content, "FEED_CONTENT")?;
+    override_opt(&mut self.site.sitemap_url, "SITEMAP_URL")?;
+
+    override_opt(&mut self.auth.jwt_algorithm, "JWT_ALGORITHM")?;
+    override_opt(&mut self.auth.jwt_secret, "JWT_SECRET")?;
+    override_opt(&mut self.auth.jwt_public_key, "JWT_PUBLIC_KEY")?;
+    override_opt(&mut self.auth.jwt_issuer, "JWT_ISSUER")?;
+    override_opt(&mut self.auth.jwt_audience, "JWT_AUDIENCE")?;
+    override_with(&mut self.auth.jwt_leeway, "JWT_LEEWAY")?;
+    override_opt(&mut self.auth.admin_token, "ADMIN_TOKEN")?;
+
+    override_opt(&mut self.preview.secret, "PREVIEW_SECRET")?;
+    override_with(&mut self.preview.max_expires_in, "PREVIEW_MAX_EXPIRES_IN")?;
+
+    override_with(&mut self.log.format, "LOG_FORMAT")?;
+    override_with(&mut self.log.level, "LOG_LEVEL")?;
+
+    override_with(&mut self.limits.max_depth, "MAX_QUERY_DEPTH")?;
+    override_with(&mut self.limits.max_complexity, "MAX_QUERY_COMPLEXITY")?;
+    override_with(&mut self.limits.max_aliases, "MAX_QUERY_ALIASES")?;
+    override_with(&mut self.limits.max_root_fields, "MAX_QUERY_ROOT_FIELDS")?;
+    override_with(&mut self.limits.max_introspection_depth, "MAX_INTROSPECTION_DEPTH")?;
+
+    override_with(&mut self.persisted_queries.enabled, "PERSISTED_QUERIES_ENABLED")?;
+    override_with(&mut self.persisted_queries.capacity, "PERSISTED_QUERIES_CAPACITY")?;
+
+    override_with(&mut self.cache.enabled, "CACHE_ENABLED")?;
+    override_with(&mut self.cache.ttl, "CACHE_TTL")?;
+    override_with(&mut self.cache.capacity, "CACHE_CAPACITY")?;
+
+    override_with(&mut self.rate_limit.enabled, "RATE_LIMIT_ENABLED")?;
+    override_with(&mut self.rate_limit.queries_per_minute, "RATE_LIMIT_QUERIES_PER_MINUTE")?;
+    override_with(&mut self.rate_limit.query_burst, "RATE_LIMIT_QUERY_BURST")?;
+    override_with(&mut self.rate_limit.mutations_per_minute, "RATE_LIMIT_MUTATIONS_PER_MINUTE")?;
+    override_with(&mut self.rate_limit.mutation_burst, "RATE_LIMIT_MUTATION_BURST")?;
+    if let Some(proxies) = env_value("RATE_LIMIT_TRUSTED_PROXIES")? {
+      self.rate_limit.trusted_proxies = split_list(&proxies);
+    }
+    if let Some(keys) = env_value("RATE_LIMIT_API_KEYS")? {
+      self.rate_limit.api_keys = split_list(&keys);
+    }
+    override_with(&mut self.rate_limit.max_clients, "RATE_LIMIT_MAX_CLIENTS")?;
+    Ok(())
+  }
+
+  fn validate(&mut self) -> Result<(), ConfigError> {
+    let invalid = |message: &str| Err(ConfigError::Invalid(message.to_string()));
+
+    if self.server.host.parse::<IpAddr>().is_err() {
+      return invalid("server.host must be an IP address");
+    }
+    if self.database.url.is_empty() {
+      return invalid("database.url (DATABASE_URL) is not set");
+    }
+    if self.database.max_connections == 0 {
+      return invalid("database.max_connections must be positive");
+    }
+    if self.database.min_connections > self.database.max_connections {
+      return invalid("database.min_connections must not exceed database.max_connections");
+    }
+    if self.server.cors_origins.iter().any(|origin| origin.parse::<axum::http::HeaderValue>().is_err()) {
+      return invalid("server.cors_origins contains an invalid origin");
+    }
+    if !(1..=100).contains(&self.posts.page_size) {
+      return invalid("posts.page_size must be between 1 and 100");
+    }
+    if self.posts.excerpt_length <= 0 {
+      return invalid("posts.excerpt_length must be positive");
+    }
+    if self.persisted_queries.enabled && self.persisted_queries.capacity == 0 {
+      return invalid("persisted_queries.capacity must be positive");
+    }
+    if self.cache.enabled && self.cache.capacity == 0 {
+      return invalid("cache.capacity must be positive");
+    }
+    if self.rate_limit.enabled {
+      let rate_limit = &self.rate_limit;
+      if rate_limit.queries_per_minute == 0 || rate_limit.query_burst == 0 {
+        return invalid("rate_limit.queries_per_minute and rate_limit.query_burst must be positive");
+      }
+      if rate_limit.mutations_per_minute == 0 || rate_limit.mutation_burst == 0 {
+        return invalid("rate_limit.mutations_per_minute and rate_limit.mutation_burst must be positive");
+      }
+      if rate_limit.max_clients == 0 {
+        return invalid("rate_limit.max_clients must be positive");
+      }
+      if rate_limit.trusted_proxies.iter().any(|proxy| proxy.parse::<TrustedProxy>().is_err()) {
+        return invalid("rate_limit.trusted_proxies must be IP addresses or CIDR ranges");
+      }
+      if rate_limit.api_keys.iter().any(|key| key.is_empty()) {
+        return invalid("rate_limit.api_keys must not contain an empty key");
+      }
+    }
+    if self.site.feed_size <= 0 {
+      return invalid("site.feed_size must be positive");
+    }
+    match self.auth.jwt_algorithm.as_deref() {
+      None => (),
+      Some("HS256") if self.auth.jwt_secret.is_none() => {
+        return invalid("auth.jwt_secret (JWT_SECRET) is required for HS256");
+      },
+      Some("RS256") if self.auth.jwt_public_key.is_none() => {
+        return invalid("auth.jwt_public_key (JWT_PUBLIC_KEY) is required for RS256");
+      },
+      Some("HS256") | Some("RS256") => (),
+      Some(_) => return invalid("auth.jwt_algorithm must be HS256 or RS256"),
+    }
+    // 有効期限の計算が溢れないよう、1年までにしておく
+    if !(1..=60 * 60 * 24 * 365).contains(&self.preview.max_expires_in) {
+      return invalid("preview.max_expires_in must be between 1 and 31536000 (1 year)");
+    }
+    if EnvFilter::try_new(&self.log.level).is_err() {
+      return invalid("log.level is not a valid filter");
+    }
+    // 空文字の秘密鍵は未設定として扱う
+    if self.preview.secret.as_deref() == Some("") {
+      self.preview.secret = None;
+    }
+    if self.auth.admin_token.as_deref() == Some("") {
+      self.auth.admin_token = None;
+    }
+
+    // URL の末尾の / は付けない形に揃える
+    self.site.url = self.site.url.trim_end_matches('/').to_string();
+    self.site.api_url = self.site.api_url.trim_end_matches('/').to_string();
+    self.site.sitemap_url = self.site.sitemap_url.as_deref().map(|url| url.trim_end_matches('/').to_string());
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // テストは並列に走るので、環境変数はテストごとに別の名前を使う
+  fn temp_file(name: &str, contents: &str) -> String {
+    let path = env::temp_dir().join(format!("api-config-test-{}-{}", std::process::id(), name));
+    fs::write(&path, contents).unwrap();
+    path.to_string_lossy().to_string()
+  }
+
+  fn valid() -> Config {
+    let mut config = Config::default();
+    config.database.url = "mysql://localhost/blog".to_string();
+    config
+  }
+
+  fn assert_invalid(mut config: Config, key: &str) {
+    match config.validate() {
+      Err(ConfigError::Invalid(message)) => assert!(message.contains(key), "{} does not mention {}", message, key),
+      Err(e) => panic!("unexpected error: {}", e),
+      Ok(()) => panic!("{} should be invalid", key),
+    }
+  }
+
+  /**
+   * env
+   */
+
+  #[test]
+  fn env_value_reads_the_variable() {
+    env::set_var("CONFIG_TEST_PLAIN", "value");
+    assert_eq!(env_value("CONFIG_TEST_PLAIN").unwrap(), Some("value".to_string()));
+  }
+
+  #[test]
+  fn env_value_is_none_when_unset() {
+    assert_eq!(env_value("CONFIG_TEST_UNSET").unwrap(), None);
+  }
+
+  #[test]
+  fn env_value_reads_the_file_and_trims_trailing_newline() {
+    let path = temp_file("secret", "s3cret\n");
+    env::set_var("CONFIG_TEST_SECRET_FILE", &path);
+    assert_eq!(env_value("CONFIG_TEST_SECRET").unwrap(), Some("s3cret".to_string()));
+  }
+
+  #[test]
+  fn env_value_rejects_both_variable_and_file() {
+    let path = temp_file("both", "from file");
+    env::set_var("CONFIG_TEST_BOTH", "from env");
+    env::set_var("CONFIG_TEST_BOTH_FILE", &path);
+    assert!(matches!(env_value("CONFIG_TEST_BOTH"), Err(ConfigError::Env { .. })));
+  }
+
+  #[test]
+  fn env_value_reports_missing_file() {
+    env::set_var("CONFIG_TEST_MISSING_FILE", "/nonexistent/api-config-test");
+    assert!(matches!(env_value("CONFIG_TEST_MISSING"), Err(ConfigError::Read { .. })));
+  }
+
+  #[test]
+  fn env_overrides_file() {
+    let mut config: Config = toml::from_str("[server]\nport = 9000\n").unwrap();
+    assert_eq!(config.server.port, 9000);
+
+    override_with(&mut config.server.port, "CONFIG_TEST_PORT_UNSET").unwrap();
+    assert_eq!(config.server.port, 9000);
+
+    env::set_var("CONFIG_TEST_PORT", "9100");
+    override_with(&mut config.server.port, "CONFIG_TEST_PORT").unwrap();
+    assert_eq!(config.server.port, 9100);
+  }
+
+  #[test]
+  fn env_override_reports_parse_errors() {
+    let mut port: u16 = 8000;
+    env::set_var("CONFIG_TEST_BAD_PORT", "eighty");
+    match override_with(&mut port, "CONFIG_TEST_BAD_PORT") {
+      Err(ConfigError::Env { key, .. }) => assert_eq!(key, "CONFIG_TEST_BAD_PORT"),
+      _ => panic!("expected an env error"),
+    }
+    assert_eq!(port, 8000);
+  }
+
+  #[test]
+  fn env_override_of_optional_values() {
+    let mut secret = None;
+    override_opt(&mut secret, "CONFIG_TEST_OPT_UNSET").unwrap();
+    assert_eq!(secret, None);
+    env::set_var("CONFIG_TEST_OPT", "value");
+    override_opt(&mut secret, "CONFIG_TEST_OPT").unwrap();
+    assert_eq!(secret, Some("value".to_string()));
+  }
+
+  #[test]
+  fn split_list_drops_empty_items() {
+    assert_eq!(split_list(" a, b ,,c,"), vec!["a", "b", "c"]);
+    assert!(split_list("").is_empty());
+  }
+
+  /**
+   * file
+   */
+
+  #[test]
+  fn from_file_reads_toml() {
+    let path = temp_file("config.toml", "[posts]\npage_size = 10\n\n[log]\nformat = \"json\"\n");
+    let config = Config::from_file(&path).unwrap();
+    assert_eq!(config.posts.page_size, 10);
+    assert!(config.log.format == LogFormat::Json);
+    // 書かれていない項目は既定値
+    assert_eq!(config.posts.excerpt_length, 200);
+  }
+
+  #[test]
+  fn from_file_rejects_unknown_fields() {
+    let path = temp_file("unknown.toml", "[server]\nprot = 9000\n");
+    assert!(matches!(Config::from_file(&path), Err(ConfigError::Parse { .. })));
+  }
+
+  #[test]
+  fn from_file_reports_missing_file() {
+    assert!(matches!(Config::from_file("/nonexistent/config.toml"), Err(ConfigError::Read { .. })));
+  }
+
+  /**
+   * validate
+   */
+
+  #[test]
+  fn default_config_with_database_url_is_valid() {
+    assert!(valid().validate().is_ok());
+  }
+
+  #[test]
+  fn validate_server() {
+    let mut config = valid();
+    config.server.host = "localhost".to_string();
+    assert_invalid(config, "server.host");
+
+    let mut config = valid();
+    config.server.cors_origins = vec!["https://example.com\n".to_string()];
+    assert_invalid(config, "server.cors_origins");
+  }
+
+  #[test]
+  fn validate_database() {
+    assert_invalid(Config::default(), "database.url");
+
+    let mut config = valid();
+    config.database.max_connections = 0;
+    config.database.min_connections = 0;
+    assert_invalid(config, "database.max_connections");
+
+    let mut config = valid();
+    config.database.min_connections = 20;
+    assert_invalid(config, "database.min_connections");
+  }
+
+  #[test]
+  fn validate_posts() {
+    for page_size in [0, 101] {
+      let mut config = valid();
+      config.posts.page_size = page_size;
+      assert_invalid(config, "posts.page_size");
+    }
+
+    let mut config = valid();
+    config.posts.excerpt_length = 0;
+    assert_invalid(config, "posts.excerpt_length");
+  }
+
+  #[test]
+  fn validate_capacities() {
+    let mut config = valid();
+    config.persisted_queries.capacity = 0;
+    assert_invalid(config, "persisted_queries.capacity");
+
+    let mut config = valid();
+    config.cache.capacity = 0;
+    assert_invalid(config, "cache.capacity");
+
+    // 無効にしている場合は見ない
+    let mut config = valid();
+    config.cache.enabled = false;
+    config.cache.capacity = 0;
+    config.persisted_queries.enabled = false;
+    config.persisted_queries.capacity = 0;
+    assert!(config.validate().is_ok());
+  }
+
+  #[test]
+  fn validate_rate_limit() {
+    let mut config = valid();
+    config.rate_limit.query_burst = 0;
+    assert_invalid(config, "rate_limit.queries_per_minute");
+
+    let mut config = valid();
+    config.rate_limit.mutations_per_minute = 0;
+    assert_invalid(config, "rate_limit.mutations_per_minute");
+
+    let mut config = valid();
+    config.rate_limit.max_clients = 0;
+    assert_invalid(config, "rate_limit.max_clients");
+
+    let mut config = valid();
+    config.rate_limit.trusted_proxies = vec!["10.0.0.0/33".to_string()];
+    assert_invalid(config, "rate_limit.trusted_proxies");
+
+    let mut config = valid();
+    config.rate_limit.api_keys = vec!["".to_string()];
+    assert_invalid(config, "rate_limit.api_keys");
+  }
+
+  #[test]
+  fn validate_site() {
+    let mut config = valid();
+    config.site.feed_size = 0;
+    assert_invalid(config, "site.feed_size");
+  }
+
+  #[test]
+  fn validate_auth() {
+    let mut config = valid();
+    config.auth.jwt_algorithm = Some("HS256".to_string());
+    assert_invalid(config, "auth.jwt_secret");
+
+    let mut config = valid();
+    config.auth.jwt_algorithm = Some("RS256".to_string());
+    assert_invalid(config, "auth.jwt_public_key");
+
+    let mut config = valid();
+    config.auth.jwt_algorithm = Some("none".to_string());
+    assert_invalid(config, "auth.jwt_algorithm");
+
+    let mut config = valid();
+    config.auth.jwt_algorithm = Some("HS256".to_string());
+    config.auth.jwt_secret = Some("secret".to_string());
+    assert!(config.validate().is_ok());
+  }
+
+  #[test]
+  fn validate_log() {
+    let mut config = valid();
+    config.log.level = "sqlx=loud".to_string();
+    assert_invalid(config, "log.level");
+  }
+
+  #[test]
+  fn validate_preview() {
+    let mut config = valid();
+    config.preview.max_expires_in = 0;
+    assert_invalid(config, "preview.max_expires_in");
+    let mut config = valid();
+    config.preview.max_expires_in = i64::MAX;
+    assert_invalid(config, "preview.max_expires_in");
+  }
+
+  #[test]
+  fn validate_normalizes_values() {
+    let mut config = valid();
+    config.preview.secret = Some("".to_string());
+    config.auth.admin_token = Some("".to_string());
+    config.site.url = "https://example.com/".to_string();
+    config.site.api_url = "https://api.example.com//".to_string();
+    config.site.sitemap_url = Some("https://example.com/".to_string());
+    config.validate().unwrap();
+
+    assert_eq!(config.preview.secret, None);
+    assert_eq!(config.auth.admin_token, None);
+    assert_eq!(config.site.url, "https://example.com");
+    assert_eq!(config.site.api_url, "https://api.example.com");
+    assert_eq!(config.site.sitemap_url.as_deref(), Some("https://example.com"));
+    assert_eq!(config.site.sitemap_url("posts-1.xml"), "https://example.com/sitemaps/posts-1.xml");
+  }
+}
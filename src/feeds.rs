@@ -0,0 +1,426 @@
+use axum::{
+  extract::{Extension, Path},
+  http::{header, HeaderMap, HeaderValue, StatusCode},
+  response::{IntoResponse, Response},
+};
+use chrono::{DateTime, TimeZone, Utc};
+use sqlx::mysql::MySqlPool;
+use std::sync::Arc;
+
+use crate::categories::get_category;
+use crate::config::{encode_path_segment, Config, FeedContent};
+use crate::filter::PostFilter;
+use crate::http_cache::{if_none_match, strong_etag};
+use crate::markdown::MarkdownRenderer;
+use crate::resolvers::{get_latest_posts, BlogError, Post};
+
+pub fn escape_xml(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&apos;"),
+      _ => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+pub fn http_date(date: DateTime<Utc>) -> String {
+  date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+// ETag / Last-Modified を付けて返す。条件付きリクエストで変更がなければ 304 にする
+pub fn conditional_response(
+  headers: &HeaderMap,
+  content_type: &'static str,
+  body: String,
+  last_modified: Option<DateTime<Utc>>,
+) -> Response {
+  // DefaultHasher はバージョンによって値が変わるので、デプロイをまたいでも同じになる sha256 を使う
+  let etag = strong_etag(body.as_bytes());
+
+  // If-None-Match がある場合は If-Modified-Since より優先する
+  let not_modified = match headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+    Some(_) => if_none_match(headers, &etag),
+    None => match (last_modified, headers.get(header::IF_MODIFIED_SINCE).and_then(|v| v.to_str().ok())) {
+      (Some(last_modified), Some(since)) => match DateTime::parse_from_rfc2822(since) {
+        // HTTP の日付は秒までしかないので、秒単位で比較する
+        Ok(since) => last_modified.timestamp() <= since.timestamp(),
+        Err(_) => false,
+      },
+      _ => false,
+    },
+  };
+
+  let mut response = match not_modified {
+    true => StatusCode::NOT_MODIFIED.into_response(),
+    false => ([(header::CONTENT_TYPE, content_type)], body).into_response(),
+  };
+  let response_headers = response.headers_mut();
+  if let Ok(etag) = HeaderValue::from_str(&etag) {
+    response_headers.insert(header::ETAG, etag);
+  }
+  if let Some(last_modified) = last_modified {
+    if let Ok(last_modified) = HeaderValue::from_str(&http_date(last_modified)) {
+      response_headers.insert(header::LAST_MODIFIED, last_modified);
+    }
+  }
+  response
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+  Rss,
+  Atom,
+}
+
+struct Feed<'a> {
+  title: String,
+  link: String,
+  self_link: String,
+  posts: &'a [Post],
+  // posts と同じ順の本文 (HTML か抜粋)
+  contents: Vec<String>,
+}
+
+async fn content(config: &Config, renderer: &MarkdownRenderer, post: &Post) -> Result<String, BlogError> {
+  let contents = post.contents.clone().unwrap_or_default();
+  Ok(match config.site.feed_content {
+    FeedContent::Full => renderer.render(post.id, post.updated_at, &contents).await?.to_string(),
+    FeedContent::Excerpt => {
+      let excerpt: String = contents.chars().take(config.posts.excerpt_length as usize).collect();
+      escape_xml(&excerpt)
+    },
+  })
+}
+
+fn rss(config: &Config, feed: &Feed) -> String {
+  let site = &config.site;
+  let mut xml = String::new();
+  xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  xml.push_str("<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n<channel>\n");
+  xml.push_str(&format!("<title>{}</title>\n", escape_xml(&feed.title)));
+  xml.push_str(&format!("<link>{}</link>\n", escape_xml(&feed.link)));
+  xml.push_str(&format!("<description>{}</description>\n", escape_xml(&site.description)));
+  xml.push_str(&format!(
+    "<atom:link href=\"{}\" rel=\"self\" type=\"application/rss+xml\"/>\n",
+    escape_xml(&feed.self_link)
+  ));
+  if let Some(updated) = feed.posts.iter().map(|post| post.updated_at).max() {
+    xml.push_str(&format!("<lastBuildDate>{}</lastBuildDate>\n", updated.to_rfc2822()));
+  }
+  for (post, content) in feed.posts.iter().zip(&feed.contents) {
+    let link = site.post_url(post.id);
+    xml.push_str("<item>\n");
+    xml.push_str(&format!("<title>{}</title>\n", escape_xml(&post.title)));
+    xml.push_str(&format!("<link>{}</link>\n", escape_xml(&link)));
+    xml.push_str(&format!("<guid isPermaLink=\"true\">{}</guid>\n", escape_xml(&link)));
+    xml.push_str(&format!("<pubDate>{}</pubDate>\n", post.pub_date.to_rfc2822()));
+    if let Some(category) = &post.category {
+      xml.push_str(&format!("<category>{}</category>\n", escape_xml(category)));
+    }
+    xml.push_str(&format!(
+      "<description>{}</description>\n",
+      escape_xml(content)
+    ));
+    xml.push_str("</item>\n");
+  }
+  xml.push_str("</channel>\n</rss>\n");
+  xml
+}
+
+fn epoch() -> DateTime<Utc> {
+  Utc.timestamp_opt(0, 0).unwrap()
+}
+
+fn atom(config: &Config, feed: &Feed) -> String {
+  let site = &config.site;
+  // 投稿がない場合も毎回同じ内容になるよう、現在時刻ではなく固定の日時にする (ETag が変わらないように)
+  let updated = feed.posts.iter().map(|post| post.updated_at).max().unwrap_or_else(epoch);
+  let content_tag = match site.feed_content {
+    FeedContent::Full => "content",
+    FeedContent::Excerpt => "summary",
+  };
+
+  let mut xml = String::new();
+  xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+  xml.push_str(&format!("<title>{}</title>\n", escape_xml(&feed.title)));
+  xml.push_str(&format!("<subtitle>{}</subtitle>\n", escape_xml(&site.description)));
+  xml.push_str(&format!("<id>{}</id>\n", escape_xml(&feed.link)));
+  xml.push_str(&format!("<link href=\"{}\"/>\n", escape_xml(&feed.link)));
+  xml.push_str(&format!("<link rel=\"self\" href=\"{}\"/>\n", escape_xml(&feed.self_link)));
+  xml.push_str(&format!("<updated>{}</updated>\n", updated.to_rfc3339()));
+  xml.push_str(&format!("<author><name>{}</name></author>\n", escape_xml(&site.title)));
+  for (post, content) in feed.posts.iter().zip(&feed.contents) {
+    let link = site.post_url(post.id);
+    xml.push_str("<entry>\n");
+    xml.push_str(&format!("<title>{}</title>\n", escape_xml(&post.title)));
+    xml.push_str(&format!("<id>{}</id>\n", escape_xml(&link)));
+    xml.push_str(&format!("<link href=\"{}\"/>\n", escape_xml(&link)));
+    xml.push_str(&format!("<published>{}</published>\n", post.pub_date.to_rfc3339()));
+    xml.push_str(&format!("<updated>{}</updated>\n", post.updated_at.to_rfc3339()));
+    if let Some(category) = &post.category {
+      xml.push_str(&format!("<category term=\"{}\"/>\n", escape_xml(category)));
+    }
+    xml.push_str(&format!(
+      "<{tag} type=\"html\">{}</{tag}>\n",
+      escape_xml(content),
+      tag = content_tag
+    ));
+    xml.push_str("</entry>\n");
+  }
+  xml.push_str("</feed>\n");
+  xml
+}
+
+async fn feed(
+  pool: &MySqlPool,
+  config: &Config,
+  renderer: &MarkdownRenderer,
+  headers: &HeaderMap,
+  category: Option<String>,
+  format: Format,
+) -> Response {
+  let site = &config.site;
+  let (title, link, path) = match &category {
+    Some(name) => {
+      match get_category(pool, name).await {
+        Ok(_) => (),
+        Err(BlogError::NotFoundCategory) => return (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+      }
+      (
+        format!("{} - {}", site.title, name),
+        site.category_url(name),
+        format!("/categories/{}", encode_path_segment(name)),
+      )
+    },
+    None => (site.title.clone(), site.url.clone(), "".to_string()),
+  };
+
+  let filter = PostFilter::category(category.unwrap_or_default());
+  let posts = match get_latest_posts(pool, &filter, site.feed_size).await {
+    Ok(posts) => posts,
+    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+  };
+  let last_modified = posts.iter().map(|post| post.updated_at).max();
+
+  let (file, content_type) = match format {
+    Format::Rss => ("feed.xml", "application/rss+xml; charset=utf-8"),
+    Format::Atom => ("atom.xml", "application/atom+xml; charset=utf-8"),
+  };
+  let mut contents = Vec::with_capacity(posts.len());
+  for post in &posts {
+    match content(config, renderer, post).await {
+      Ok(content) => contents.push(content),
+      Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+  }
+  let feed = Feed {
+    title,
+    link,
+    self_link: format!("{}{}/{}", site.api_url, path, file),
+    posts: &posts,
+    contents,
+  };
+
+  let body = match format {
+    Format::Rss => rss(config, &feed),
+    Format::Atom => atom(config, &feed),
+  };
+  conditional_response(headers, content_type, body, last_modified)
+}
+
+/**
+ * handlers
+ */
+pub async fn rss_handler(
+  Extension(pool): Extension<MySqlPool>,
+  Extension(config): Extension<Arc<Config>>,
+  Extension(renderer): Extension<Arc<MarkdownRenderer>>,
+  headers: HeaderMap,
+) -> Response {
+  feed(&pool, &config, &renderer, &headers, None, Format::Rss).await
+}
+
+pub async fn atom_handler(
+  Extension(pool): Extension<MySqlPool>,
+  Extension(config): Extension<Arc<Config>>,
+  Extension(renderer): Extension<Arc<MarkdownRenderer>>,
+  headers: HeaderMap,
+) -> Response {
+  feed(&pool, &config, &renderer, &headers, None, Format::Atom).await
+}
+
+pub async fn category_rss_handler(
+  Path(category): Path<String>,
+  Extension(pool): Extension<MySqlPool>,
+  Extension(config): Extension<Arc<Config>>,
+  Extension(renderer): Extension<Arc<MarkdownRenderer>>,
+  headers: HeaderMap,
+) -> Response {
+  feed(&pool, &config, &renderer, &headers, Some(category), Format::Rss).await
+}
+
+pub async fn category_atom_handler(
+  Path(category): Path<String>,
+  Extension(pool): Extension<MySqlPool>,
+  Extension(config): Extension<Arc<Config>>,
+  Extension(renderer): Extension<Arc<MarkdownRenderer>>,
+  headers: HeaderMap,
+) -> Response {
+  feed(&pool, &config, &renderer, &headers, Some(category), Format::Atom).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn date(s: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+  }
+
+  fn post(id: i32, title: &str, updated_at: &str) -> Post {
+    Post {
+      id,
+      title: title.to_string(),
+      category: Some("rust & web".to_string()),
+      category_id: 1,
+      contents: Some("contents".to_string()),
+      pub_date: date("2022-01-01T00:00:00Z"),
+      updated_at: date(updated_at),
+      open: 1,
+    }
+  }
+
+  fn feed<'a>(posts: &'a [Post], contents: Vec<&str>) -> Feed<'a> {
+    Feed {
+      title: "takurinton".to_string(),
+      link: "https://takurinton.dev".to_string(),
+      self_link: "https://api.takurinton.dev/feed.xml".to_string(),
+      posts,
+      contents: contents.into_iter().map(str::to_string).collect(),
+    }
+  }
+
+  fn headers(name: header::HeaderName, value: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(name, HeaderValue::from_str(value).unwrap());
+    headers
+  }
+
+  fn etag_of(response: &Response) -> String {
+    response.headers()[header::ETAG].to_str().unwrap().to_string()
+  }
+
+  #[test]
+  fn escape_xml_escapes_markup() {
+    assert_eq!(escape_xml("<a href=\"x\">Tom & 'Jerry'</a>"), "&lt;a href=&quot;x&quot;&gt;Tom &amp; &apos;Jerry&apos;&lt;/a&gt;");
+  }
+
+  #[test]
+  fn http_date_is_rfc7231() {
+    assert_eq!(http_date(date("2022-03-04T05:06:07Z")), "Fri, 04 Mar 2022 05:06:07 GMT");
+  }
+
+  #[test]
+  fn response_has_etag_and_last_modified() {
+    let last_modified = date("2022-03-04T05:06:07Z");
+    let response = conditional_response(&HeaderMap::new(), "application/rss+xml", "body".to_string(), Some(last_modified));
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(etag_of(&response), strong_etag(b"body"));
+    assert_eq!(response.headers()[header::LAST_MODIFIED], "Fri, 04 Mar 2022 05:06:07 GMT");
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/rss+xml");
+  }
+
+  #[test]
+  fn matching_etag_is_not_modified() {
+    let etag = strong_etag(b"body");
+    let response = conditional_response(&headers(header::IF_NONE_MATCH, &etag), "text/xml", "body".to_string(), None);
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(etag_of(&response), etag);
+
+    let weak = format!("W/{}", etag);
+    let response = conditional_response(&headers(header::IF_NONE_MATCH, &weak), "text/xml", "body".to_string(), None);
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+  }
+
+  #[test]
+  fn changed_etag_is_modified() {
+    let etag = strong_etag(b"old body");
+    let response = conditional_response(&headers(header::IF_NONE_MATCH, &etag), "text/xml", "body".to_string(), None);
+    assert_eq!(response.status(), StatusCode::OK);
+  }
+
+  #[test]
+  fn if_modified_since() {
+    let last_modified = date("2022-03-04T05:06:07.500Z");
+    let since = |value: &str| {
+      conditional_response(&headers(header::IF_MODIFIED_SINCE, value), "text/xml", "body".to_string(), Some(last_modified)).status()
+    };
+    // 秒未満は切り捨てて比べる
+    assert_eq!(since("Fri, 04 Mar 2022 05:06:07 GMT"), StatusCode::NOT_MODIFIED);
+    assert_eq!(since("Sat, 05 Mar 2022 00:00:00 GMT"), StatusCode::NOT_MODIFIED);
+    assert_eq!(since("Fri, 04 Mar 2022 05:06:06 GMT"), StatusCode::OK);
+    assert_eq!(since("not a date"), StatusCode::OK);
+  }
+
+  #[test]
+  fn if_none_match_takes_precedence_over_if_modified_since() {
+    let mut headers = headers(header::IF_NONE_MATCH, &strong_etag(b"old body"));
+    headers.insert(header::IF_MODIFIED_SINCE, HeaderValue::from_static("Sat, 05 Mar 2022 00:00:00 GMT"));
+    let response = conditional_response(&headers, "text/xml", "body".to_string(), Some(date("2022-03-04T05:06:07Z")));
+    assert_eq!(response.status(), StatusCode::OK);
+  }
+
+  #[test]
+  fn rss_output() {
+    let config = Config::default();
+    let posts = vec![post(2, "second <post>", "2022-02-01T00:00:00Z"), post(1, "first", "2022-01-15T00:00:00Z")];
+    let xml = rss(&config, &feed(&posts, vec!["<p>two</p>", "<p>one</p>"]));
+
+    assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss version=\"2.0\""));
+    assert!(xml.contains("<atom:link href=\"https://api.takurinton.dev/feed.xml\" rel=\"self\" type=\"application/rss+xml\"/>"));
+    assert!(xml.contains("<lastBuildDate>Tue, 1 Feb 2022 00:00:00 +0000</lastBuildDate>"));
+    assert!(xml.contains("<title>second &lt;post&gt;</title>"));
+    assert!(xml.contains("<guid isPermaLink=\"true\">https://takurinton.dev/post/2</guid>"));
+    assert!(xml.contains("<category>rust &amp; web</category>"));
+    assert!(xml.contains("<description>&lt;p&gt;two&lt;/p&gt;</description>"));
+    assert_eq!(xml.matches("<item>").count(), 2);
+    assert!(xml.find("/post/2").unwrap() < xml.find("/post/1").unwrap());
+    assert!(xml.ends_with("</channel>\n</rss>\n"));
+  }
+
+  #[test]
+  fn atom_output() {
+    let mut config = Config::default();
+    config.site.feed_content = FeedContent::Excerpt;
+    let posts = vec![post(2, "second", "2022-01-15T00:00:00Z"), post(1, "first", "2022-02-01T00:00:00Z")];
+    let xml = atom(&config, &feed(&posts, vec!["two", "one"]));
+
+    assert!(xml.contains("<feed xmlns=\"http://www.w3.org/2005/Atom\">"));
+    assert!(xml.contains("<link rel=\"self\" href=\"https://api.takurinton.dev/feed.xml\"/>"));
+    // フィードの updated は一番新しい updated_at
+    assert!(xml.contains("<updated>2022-02-01T00:00:00+00:00</updated>\n<author>"));
+    assert!(xml.contains("<id>https://takurinton.dev/post/2</id>"));
+    assert!(xml.contains("<category term=\"rust &amp; web\"/>"));
+    assert!(xml.contains("<summary type=\"html\">two</summary>"));
+    assert!(!xml.contains("<content"));
+    assert_eq!(xml.matches("<entry>").count(), 2);
+    assert!(xml.ends_with("</feed>\n"));
+  }
+
+  #[test]
+  fn empty_atom_feed_is_stable() {
+    let config = Config::default();
+    let xml = atom(&config, &feed(&[], vec![]));
+    assert!(xml.contains("<updated>1970-01-01T00:00:00+00:00</updated>"));
+    assert_eq!(xml, atom(&config, &feed(&[], vec![])));
+    assert!(!xml.contains("<entry>"));
+  }
+}
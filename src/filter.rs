@@ -0,0 +1,328 @@
+use chrono::{DateTime, Utc};
+use sqlx::{
+  mysql::{MySql, MySqlArguments},
+  Arguments,
+  Encode,
+  Type,
+};
+
+use async_graphql::{
+  Enum,
+  InputObject,
+};
+
+use crate::tags::{normalize, TagMatch};
+
+// SQL 文字列と bind する値を一緒に組み立てる
+// 値は必ず push_bind で渡し、SQL に直接埋め込まない
+pub struct QueryBuilder {
+  sql: String,
+  args: MySqlArguments,
+}
+
+impl QueryBuilder {
+  pub fn new(sql: &str) -> Self {
+    QueryBuilder {
+      sql: sql.to_string(),
+      args: MySqlArguments::default(),
+    }
+  }
+
+  pub fn push(&mut self, sql: &str) -> &mut Self {
+    self.sql.push(' ');
+    self.sql.push_str(sql);
+    self
+  }
+
+  // `?` を一つ足して値を bind する
+  pub fn push_bind<T>(&mut self, value: T) -> &mut Self
+  where
+    T: 'static + Send + Encode<'static, MySql> + Type<MySql>,
+  {
+    self.sql.push_str(" ?");
+    Arguments::add(&mut self.args, value);
+    self
+  }
+
+  // `?, ?, ?` を値の数だけ足して bind する
+  pub fn push_bind_list<T>(&mut self, values: Vec<T>) -> &mut Self
+  where
+    T: 'static + Send + Encode<'static, MySql> + Type<MySql>,
+  {
+    for (i, value) in values.into_iter().enumerate() {
+      if i > 0 {
+        self.sql.push(',');
+      }
+      self.push_bind(value);
+    }
+    self
+  }
+
+  pub fn build(self) -> (String, MySqlArguments) {
+    (self.sql, self.args)
+  }
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum Visibility {
+  /// open = true
+  #[default]
+  Published,
+  /// open = false, requires authorization
+  Draft,
+  /// both of them, requires authorization
+  All,
+}
+
+// 投稿一覧の絞り込み条件
+// 新しい条件はフィールドを足して apply に1行追加する
+#[derive(InputObject, Clone, Default, Debug)]
+pub struct PostFilter {
+  /// name of the category
+  pub category: Option<String>,
+  /// names of the categories, matches any of them
+  pub categories: Option<Vec<String>>,
+  /// pub_date >= pubDateFrom
+  pub pub_date_from: Option<DateTime<Utc>>,
+  /// pub_date < pubDateTo
+  pub pub_date_to: Option<DateTime<Utc>>,
+  /// part of the title
+  pub title_contains: Option<String>,
+  /// names of the tags
+  pub tags: Option<Vec<String>>,
+  /// ANY or ALL of the tags
+  #[graphql(default)]
+  pub tag_match: TagMatch,
+  #[graphql(default)]
+  pub visibility: Visibility,
+}
+
+impl PostFilter {
+  pub fn category(name: String) -> Self {
+    PostFilter {
+      category: match name.is_empty() {
+        true => None,
+        false => Some(name),
+      },
+      ..PostFilter::default()
+    }
+  }
+
+  // blogapp_post と blogapp_category を JOIN した WHERE の後ろに条件を足す
+  pub fn apply(&self, query: &mut QueryBuilder) {
+    match self.visibility {
+      Visibility::Published => { query.push("AND open = true"); },
+      Visibility::Draft => { query.push("AND open = false"); },
+      Visibility::All => (),
+    }
+    if let Some(category) = &self.category {
+      query.push("AND blogapp_category.name =").push_bind(category.clone());
+    }
+    if let Some(categories) = &self.categories {
+      match categories.is_empty() {
+        // 空のリストは何にもマッチさせない
+        true => { query.push("AND false"); },
+        false => { query.push("AND blogapp_category.name IN (").push_bind_list(categories.clone()).push(")"); },
+      }
+    }
+    if let Some(from) = self.pub_date_from {
+      query.push("AND pub_date >=").push_bind(from);
+    }
+    if let Some(to) = self.pub_date_to {
+      query.push("AND pub_date <").push_bind(to);
+    }
+    if let Some(title) = &self.title_contains {
+      query.push("AND title LIKE").push_bind(format!("%{}%", escape_like(title)));
+    }
+    if let Some(tags) = &self.tags {
+      let tags = normalize(tags.clone());
+      match tags.is_empty() {
+        true => { query.push("AND false"); },
+        false => self.apply_tags(query, tags),
+      }
+    }
+  }
+
+  // タグ名で絞り込んだ post_id のサブクエリ
+  // ALL の場合は指定されたタグを全て持つ投稿だけ残す
+  fn apply_tags(&self, query: &mut QueryBuilder, tags: Vec<String>) {
+    let count = tags.len() as i64;
+    query
+      .push("AND blogapp_post.id IN (")
+      .push("SELECT blogapp_post_tags.post_id FROM blogapp_post_tags")
+      .push("INNER JOIN blogapp_tag ON blogapp_post_tags.tag_id = blogapp_tag.id")
+      .push("WHERE blogapp_tag.name IN (")
+      .push_bind_list(tags)
+      .push(")");
+    if self.tag_match == TagMatch::All {
+      query
+        .push("GROUP BY blogapp_post_tags.post_id HAVING count(DISTINCT blogapp_tag.id) =")
+        .push_bind(count);
+    }
+    query.push(")");
+  }
+}
+
+// LIKE のワイルドカードとして解釈されないようにする
+fn escape_like(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    if c == '\\' || c == '%' || c == '_' {
+      escaped.push('\\');
+    }
+    escaped.push(c);
+  }
+  escaped
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn date(s: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+  }
+
+  fn build(filter: &PostFilter) -> (String, MySqlArguments) {
+    let mut query = QueryBuilder::new("WHERE true");
+    filter.apply(&mut query);
+    query.build()
+  }
+
+  // MySqlArguments は比較できないので、エンコード結果を Debug 出力で比べる
+  fn assert_args(actual: &MySqlArguments, expected: &MySqlArguments) {
+    assert_eq!(format!("{:?}", actual), format!("{:?}", expected));
+  }
+
+  #[test]
+  fn escape_like_escapes_wildcards_and_backslash() {
+    assert_eq!(escape_like("100%"), "100\\%");
+    assert_eq!(escape_like("snake_case"), "snake\\_case");
+    assert_eq!(escape_like("C:\\path"), "C:\\\\path");
+    assert_eq!(escape_like("%_\\"), "\\%\\_\\\\");
+  }
+
+  #[test]
+  fn escape_like_keeps_other_characters() {
+    assert_eq!(escape_like("日本語 'quoted' \"double\""), "日本語 'quoted' \"double\"");
+    assert_eq!(escape_like(""), "");
+  }
+
+  #[test]
+  fn default_filter_only_shows_published_posts() {
+    let (sql, args) = build(&PostFilter::default());
+    assert_eq!(sql, "WHERE true AND open = true");
+    assert_args(&args, &MySqlArguments::default());
+  }
+
+  #[test]
+  fn visibility() {
+    let draft = PostFilter { visibility: Visibility::Draft, ..PostFilter::default() };
+    assert_eq!(build(&draft).0, "WHERE true AND open = false");
+    let all = PostFilter { visibility: Visibility::All, ..PostFilter::default() };
+    assert_eq!(build(&all).0, "WHERE true");
+  }
+
+  #[test]
+  fn empty_category_means_all_categories() {
+    assert_eq!(build(&PostFilter::category("".to_string())).0, "WHERE true AND open = true");
+  }
+
+  #[test]
+  fn empty_categories_match_nothing() {
+    let filter = PostFilter { categories: Some(vec![]), ..PostFilter::default() };
+    let (sql, args) = build(&filter);
+    assert_eq!(sql, "WHERE true AND open = true AND false");
+    assert_args(&args, &MySqlArguments::default());
+  }
+
+  #[test]
+  fn values_are_bound_in_placeholder_order() {
+    let from = date("2022-01-01T00:00:00Z");
+    let to = date("2023-01-01T00:00:00Z");
+    let filter = PostFilter {
+      category: Some("rust".to_string()),
+      categories: Some(vec!["a".to_string(), "b".to_string()]),
+      pub_date_from: Some(from),
+      pub_date_to: Some(to),
+      title_contains: Some("50%_off".to_string()),
+      tags: Some(vec!["web".to_string()]),
+      tag_match: TagMatch::Any,
+      visibility: Visibility::Published,
+    };
+    let (sql, args) = build(&filter);
+
+    assert_eq!(
+      sql,
+      "WHERE true AND open = true \
+       AND blogapp_category.name = ? \
+       AND blogapp_category.name IN ( ?, ? ) \
+       AND pub_date >= ? \
+       AND pub_date < ? \
+       AND title LIKE ? \
+       AND blogapp_post.id IN ( \
+       SELECT blogapp_post_tags.post_id FROM blogapp_post_tags \
+       INNER JOIN blogapp_tag ON blogapp_post_tags.tag_id = blogapp_tag.id \
+       WHERE blogapp_tag.name IN ( ? ) )"
+    );
+    let mut expected = MySqlArguments::default();
+    Arguments::add(&mut expected, "rust".to_string());
+    Arguments::add(&mut expected, "a".to_string());
+    Arguments::add(&mut expected, "b".to_string());
+    Arguments::add(&mut expected, from);
+    Arguments::add(&mut expected, to);
+    Arguments::add(&mut expected, "%50\\%\\_off%".to_string());
+    Arguments::add(&mut expected, "web".to_string());
+    assert_args(&args, &expected);
+  }
+
+  #[test]
+  fn all_tags_require_every_tag() {
+    let filter = PostFilter {
+      tags: Some(vec![" Rust ".to_string(), "web".to_string(), "rust".to_string()]),
+      tag_match: TagMatch::All,
+      ..PostFilter::default()
+    };
+    let (sql, args) = build(&filter);
+    assert_eq!(
+      sql,
+      "WHERE true AND open = true \
+       AND blogapp_post.id IN ( \
+       SELECT blogapp_post_tags.post_id FROM blogapp_post_tags \
+       INNER JOIN blogapp_tag ON blogapp_post_tags.tag_id = blogapp_tag.id \
+       WHERE blogapp_tag.name IN ( ?, ? ) \
+       GROUP BY blogapp_post_tags.post_id HAVING count(DISTINCT blogapp_tag.id) = ? )"
+    );
+    // 正規化して重複を除いた後のタグ数と比べる
+    let mut expected = MySqlArguments::default();
+    Arguments::add(&mut expected, "rust".to_string());
+    Arguments::add(&mut expected, "web".to_string());
+    Arguments::add(&mut expected, 2i64);
+    assert_args(&args, &expected);
+  }
+
+  #[test]
+  fn blank_tags_match_nothing() {
+    let filter = PostFilter { tags: Some(vec![" ".to_string()]), ..PostFilter::default() };
+    let (sql, args) = build(&filter);
+    assert_eq!(sql, "WHERE true AND open = true AND false");
+    assert_args(&args, &MySqlArguments::default());
+  }
+
+  #[test]
+  fn user_input_never_reaches_the_sql() {
+    let category = "' OR 1=1 --".to_string();
+    let title = "'; DROP TABLE blogapp_post; --".to_string();
+    let filter = PostFilter {
+      category: Some(category.clone()),
+      title_contains: Some(title.clone()),
+      ..PostFilter::default()
+    };
+    let (sql, args) = build(&filter);
+    assert_eq!(sql, "WHERE true AND open = true AND blogapp_category.name = ? AND title LIKE ?");
+    let mut expected = MySqlArguments::default();
+    Arguments::add(&mut expected, category);
+    Arguments::add(&mut expected, format!("%{}%", escape_like(&title)));
+    assert_args(&args, &expected);
+  }
+}
@@ -0,0 +1,176 @@
+use axum::{
+  extract::Extension,
+  http::StatusCode,
+  response::IntoResponse,
+  Json,
+};
+use serde::Serialize;
+use sqlx::{migrate::Migrator, mysql::MySqlPool};
+use std::{
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
+
+use async_graphql::SimpleObject;
+
+// `api migrate` で適用し、readyz では全て適用済みかを確認する
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+// ビルド時に BUILD_VERSION (git のコミットなど) を渡せばそれを使う
+pub const VERSION: &str = match option_env!("BUILD_VERSION") {
+  Some(version) => version,
+  None => env!("CARGO_PKG_VERSION"),
+};
+
+// 死んだコネクションで待たされ続けないように打ち切る
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// 起動からの経過時間と、シャットダウン中かどうか
+// SIGTERM を受け取ったら draining にして、ロードバランサから外してもらう
+pub struct Readiness {
+  started: Instant,
+  draining: AtomicBool,
+}
+
+impl Readiness {
+  pub fn new() -> Self {
+    Readiness {
+      started: Instant::now(),
+      draining: AtomicBool::new(false),
+    }
+  }
+
+  pub fn start_draining(&self) {
+    self.draining.store(true, Ordering::SeqCst);
+  }
+
+  pub fn is_draining(&self) -> bool {
+    self.draining.load(Ordering::SeqCst)
+  }
+
+  pub fn uptime(&self) -> u64 {
+    self.started.elapsed().as_secs()
+  }
+}
+
+impl Default for Readiness {
+  fn default() -> Self {
+    Readiness::new()
+  }
+}
+
+#[derive(SimpleObject, Serialize)]
+pub struct DatabaseHealth {
+  pub ok: bool,
+  pub latency_ms: f64,
+  pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MigrationHealth {
+  ok: bool,
+  expected: usize,
+  applied: usize,
+  error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Liveness {
+  status: &'static str,
+  version: &'static str,
+  uptime_seconds: u64,
+}
+
+#[derive(Serialize)]
+struct ReadinessReport {
+  status: &'static str,
+  draining: bool,
+  database: DatabaseHealth,
+  migrations: MigrationHealth,
+}
+
+/**
+ * checks
+ */
+// SELECT 1 が返ってくるまでの時間を測る
+pub async fn check_database(pool: &MySqlPool) -> DatabaseHealth {
+  let started = Instant::now();
+  let result = tokio::time::timeout(CHECK_TIMEOUT, sqlx::query("SELECT 1").execute(pool)).await;
+  let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+  let error = match result {
+    Ok(Ok(_)) => None,
+    Ok(Err(e)) => Some(e.to_string()),
+    Err(_) => Some("timed out".to_string()),
+  };
+  DatabaseHealth {
+    ok: error.is_none(),
+    latency_ms,
+    error,
+  }
+}
+
+// このバイナリに含まれるマイグレーションが全て適用済みか
+async fn check_migrations(pool: &MySqlPool) -> MigrationHealth {
+  let expected = MIGRATOR.iter().count();
+  let query = sqlx::query_as::<_, (i64,)>("SELECT version FROM _sqlx_migrations WHERE success = true").fetch_all(pool);
+  let applied = match tokio::time::timeout(CHECK_TIMEOUT, query).await {
+    Ok(Ok(rows)) => rows.into_iter().map(|(version,)| version).collect::<Vec<_>>(),
+    Ok(Err(e)) => return MigrationHealth { ok: false, expected, applied: 0, error: Some(e.to_string()) },
+    Err(_) => return MigrationHealth { ok: false, expected, applied: 0, error: Some("timed out".to_string()) },
+  };
+  MigrationHealth {
+    ok: MIGRATOR.iter().all(|migration| applied.contains(&migration.version)),
+    expected,
+    applied: applied.len(),
+    error: None,
+  }
+}
+
+/**
+ * handlers
+ */
+// プロセスが応答できれば 200。DB の状態は見ない
+pub async fn healthz_handler(Extension(readiness): Extension<Arc<Readiness>>) -> impl IntoResponse {
+  Json(Liveness {
+    status: "ok",
+    version: VERSION,
+    uptime_seconds: readiness.uptime(),
+  })
+}
+
+// DB に繋がり、マイグレーションが適用済みで、シャットダウン中でなければ 200
+pub async fn readyz_handler(
+  Extension(pool): Extension<MySqlPool>,
+  Extension(readiness): Extension<Arc<Readiness>>,
+) -> impl IntoResponse {
+  let draining = readiness.is_draining();
+  let database = check_database(&pool).await;
+  let migrations = match database.ok {
+    true => check_migrations(&pool).await,
+    false => MigrationHealth {
+      ok: false,
+      expected: MIGRATOR.iter().count(),
+      applied: 0,
+      error: Some("database is unreachable".to_string()),
+    },
+  };
+
+  let ready = !draining && database.ok && migrations.ok;
+  let (code, status) = match (ready, draining) {
+    (true, _) => (StatusCode::OK, "ok"),
+    (false, true) => (StatusCode::SERVICE_UNAVAILABLE, "draining"),
+    (false, false) => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+  };
+  (
+    code,
+    Json(ReadinessReport {
+      status,
+      draining,
+      database,
+      migrations,
+    }),
+  )
+}
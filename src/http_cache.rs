@@ -0,0 +1,150 @@
+use axum::{
+  http::{header, HeaderMap, HeaderValue, StatusCode},
+  response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc,
+};
+
+use async_graphql::Context;
+
+// キャッシュさせないレスポンス (mutation、エラー、NoStore を付けたフィールド、どのフィールドにも max-age がないもの)
+const NO_STORE: &str = "no-store";
+
+// 結果をキャッシュさせてはいけないフィールドが実行された印。リクエストごとに載せる
+// async-graphql 3 の cache_control は max_age = 0 を「ヒントなし」として扱うので、
+// { getPosts ping } のように max-age のあるフィールドと一緒に頼まれると max-age が付いてしまう
+#[derive(Default)]
+pub struct NoStore(AtomicBool);
+
+impl NoStore {
+  // WebSocket などリクエストに載っていない場合は何もしない
+  pub fn mark(ctx: &Context<'_>) {
+    if let Some(no_store) = ctx.data_opt::<Arc<NoStore>>() {
+      no_store.0.store(true, Ordering::Relaxed);
+    }
+  }
+
+  pub fn is_marked(&self) -> bool {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+// 本文のバイト列が同じ場合だけ一致する strong ETag
+pub fn strong_etag(body: &[u8]) -> String {
+  let digest: String = Sha256::digest(body).iter().map(|byte| format!("{:02x}", byte)).collect();
+  format!("\"{}\"", digest)
+}
+
+// If-None-Match は弱い比較なので、W/ を外して比べる
+pub fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+  match headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+    Some(tags) => tags.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag),
+    None => false,
+  }
+}
+
+// GraphQL のレスポンスに Cache-Control と ETag を付ける
+// max-age はクエリしたフィールドの cache_control のうち一番短いもの (ヒントのないフィールドは数に入らない)
+// 認証済みのリクエストは下書きなどを含みうるので、共有キャッシュには載せない
+pub fn graphql_response(
+  headers: &HeaderMap,
+  response: async_graphql::Response,
+  authenticated: bool,
+  no_store: bool,
+) -> Response {
+  let cache_control = match (response.is_ok(), response.cache_control.value()) {
+    _ if no_store => None,
+    (true, Some(value)) if authenticated && !value.contains("private") => Some(format!("{}, private", value)),
+    (true, Some(value)) => Some(value),
+    _ => None,
+  };
+  let body = match serde_json::to_vec(&response) {
+    Ok(body) => body,
+    Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+  };
+
+  let cache_control = match cache_control {
+    Some(cache_control) => cache_control,
+    None => {
+      return (
+        [(header::CONTENT_TYPE, "application/json"), (header::CACHE_CONTROL, NO_STORE)],
+        body,
+      )
+        .into_response();
+    },
+  };
+
+  let etag = strong_etag(&body);
+  let mut response = match if_none_match(headers, &etag) {
+    true => StatusCode::NOT_MODIFIED.into_response(),
+    false => ([(header::CONTENT_TYPE, "application/json")], body).into_response(),
+  };
+  let response_headers = response.headers_mut();
+  if let Ok(etag) = HeaderValue::from_str(&etag) {
+    response_headers.insert(header::ETAG, etag);
+  }
+  if let Ok(cache_control) = HeaderValue::from_str(&cache_control) {
+    response_headers.insert(header::CACHE_CONTROL, cache_control);
+  }
+  // 同じクエリでも Authorization によって結果が変わる
+  response_headers.append(header::VARY, HeaderValue::from_static("Authorization"));
+  response
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use async_graphql::{CacheControl, ServerError, Value};
+
+  fn response(max_age: usize) -> async_graphql::Response {
+    let mut response = async_graphql::Response::new(Value::Null);
+    response.cache_control = CacheControl { public: true, max_age };
+    response
+  }
+
+  fn cache_control(response: Response) -> String {
+    response.headers()[header::CACHE_CONTROL].to_str().unwrap().to_string()
+  }
+
+  #[test]
+  fn max_age_from_hints() {
+    let response = graphql_response(&HeaderMap::new(), response(60), false, false);
+    assert_eq!(cache_control(response), "max-age=60");
+  }
+
+  #[test]
+  fn authenticated_responses_are_private() {
+    let response = graphql_response(&HeaderMap::new(), response(60), true, false);
+    assert_eq!(cache_control(response), "max-age=60, private");
+  }
+
+  #[test]
+  fn no_store_overrides_hints() {
+    let response = graphql_response(&HeaderMap::new(), response(60), false, true);
+    assert_eq!(cache_control(response), NO_STORE);
+  }
+
+  #[test]
+  fn no_hints_and_errors_are_not_stored() {
+    let unhinted = graphql_response(&HeaderMap::new(), response(0), false, false);
+    assert_eq!(cache_control(unhinted), NO_STORE);
+
+    let mut failed = response(60);
+    failed.errors.push(ServerError::new("error", None));
+    let failed = graphql_response(&HeaderMap::new(), failed, false, false);
+    assert_eq!(cache_control(failed), NO_STORE);
+  }
+
+  #[test]
+  fn not_modified_when_etag_matches() {
+    let fresh = graphql_response(&HeaderMap::new(), response(60), false, false);
+    let etag = fresh.headers()[header::ETAG].clone();
+    let mut headers = HeaderMap::new();
+    headers.insert(header::IF_NONE_MATCH, etag);
+    let cached = graphql_response(&headers, response(60), false, false);
+    assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+  }
+}
@@ -0,0 +1,214 @@
+use serde::Deserialize;
+use std::sync::Arc;
+
+use async_graphql::{
+  extensions::{
+    Extension,
+    ExtensionContext,
+    ExtensionFactory,
+    NextParseQuery,
+  },
+  parser::types::{DocumentOperations, ExecutableDocument, OperationType},
+  ErrorExtensionValues,
+  Request,
+  ServerError,
+  ServerResult,
+  Variables,
+};
+use axum::http::{header, HeaderMap};
+
+// GET で受け取ったリクエストに載せる印
+// 実行する operation の種類は APQ でクエリ本文を取り出した後でないと分からないので、parse_query で確認する
+pub struct GetRequest {
+  operation_name: Option<String>,
+}
+
+// ブラウザでアクセスした場合は playground を返す
+pub fn wants_html(headers: &HeaderMap) -> bool {
+  match headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) {
+    Some(accept) => accept.split(',').any(|media_type| media_type.trim().starts_with("text/html")),
+    None => false,
+  }
+}
+
+// GET のクエリ文字列。variables と extensions は JSON 文字列で渡す
+// APQ のハッシュだけを送る場合は query がない
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GetParams {
+  query: Option<String>,
+  operation_name: Option<String>,
+  variables: Option<String>,
+  extensions: Option<String>,
+}
+
+// ?query=...&variables=...&operationName=...&extensions=... を Request にする
+pub fn parse_request(query_string: &str) -> Result<Request, String> {
+  let params: GetParams = serde_urlencoded::from_str(query_string).map_err(|e| e.to_string())?;
+  let mut request = Request::new(params.query.unwrap_or_default());
+  if let Some(operation_name) = params.operation_name {
+    request = request.operation_name(operation_name);
+  }
+  if let Some(variables) = params.variables {
+    let variables = serde_json::from_str(&variables).map_err(|e| format!("invalid variables: {}", e))?;
+    request = request.variables(Variables::from_json(variables));
+  }
+  if let Some(extensions) = params.extensions {
+    request.extensions = serde_json::from_str(&extensions).map_err(|e| format!("invalid extensions: {}", e))?;
+  }
+  let get_request = GetRequest {
+    operation_name: request.operation_name.clone(),
+  };
+  Ok(request.data(get_request))
+}
+
+// 実行される operation の種類
+// operationName がない場合は operation が一つだけのときに限り実行される (複数あれば実行時にエラーになる)
+pub fn operation_type(document: &ExecutableDocument, operation_name: Option<&str>) -> Option<OperationType> {
+  match (operation_name, &document.operations) {
+    (_, DocumentOperations::Single(operation)) => Some(operation.node.ty),
+    (None, DocumentOperations::Multiple(_)) => None,
+    (Some(name), operations) => operations
+      .iter()
+      .find(|(operation_name, _)| operation_name.map(|n| n.as_str()) == Some(name))
+      .map(|(_, operation)| operation.node.ty),
+  }
+}
+
+fn method_not_allowed(operation_type: OperationType) -> ServerError {
+  let mut extensions = ErrorExtensionValues::default();
+  extensions.set("code", "METHOD_NOT_ALLOWED");
+  let mut error = ServerError::new(format!("{} operations must be sent with POST", operation_type), None);
+  error.extensions = Some(extensions);
+  error
+}
+
+// GET は CDN やブラウザにキャッシュされるので、query 以外 (mutation / subscription) は実行しない
+pub struct QueryOnly;
+
+impl ExtensionFactory for QueryOnly {
+  fn create(&self) -> Arc<dyn Extension> {
+    Arc::new(QueryOnlyExtension)
+  }
+}
+
+struct QueryOnlyExtension;
+
+#[async_trait::async_trait]
+impl Extension for QueryOnlyExtension {
+  async fn parse_query(
+    &self,
+    ctx: &ExtensionContext<'_>,
+    query: &str,
+    variables: &Variables,
+    next: NextParseQuery<'_>,
+  ) -> ServerResult<ExecutableDocument> {
+    let document = next.run(ctx, query, variables).await?;
+    let get_request = match ctx.data_opt::<GetRequest>() {
+      Some(get_request) => get_request,
+      None => return Ok(document),
+    };
+
+    match operation_type(&document, get_request.operation_name.as_deref()) {
+      Some(ty) if ty != OperationType::Query => Err(method_not_allowed(ty)),
+      // 見つからない場合のエラーは実行時に返る
+      _ => Ok(document),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use async_graphql::{EmptySubscription, Object, Schema, Value};
+  use axum::http::HeaderValue;
+
+  fn accept(value: &'static str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+    headers
+  }
+
+  #[test]
+  fn browsers_want_html() {
+    assert!(wants_html(&accept("text/html,application/xhtml+xml,*/*;q=0.8")));
+    assert!(wants_html(&accept("application/json, text/html;q=0.9")));
+    assert!(!wants_html(&accept("application/json")));
+    assert!(!wants_html(&HeaderMap::new()));
+  }
+
+  #[test]
+  fn parses_query_string() {
+    let request = parse_request(
+      "query=query%20Post(%24id%3A%20Int!)%20%7B%20getPost(id%3A%20%24id)%20%7B%20title%20%7D%20%7D\
+       &operationName=Post&variables=%7B%22id%22%3A1%7D",
+    )
+    .unwrap();
+    assert_eq!(request.query, "query Post($id: Int!) { getPost(id: $id) { title } }");
+    assert_eq!(request.operation_name.as_deref(), Some("Post"));
+    assert_eq!(request.variables.get("id"), Some(&Value::from(1)));
+  }
+
+  #[test]
+  fn parses_extensions_without_query() {
+    let request = parse_request(
+      "extensions=%7B%22persistedQuery%22%3A%7B%22version%22%3A1%2C%22sha256Hash%22%3A%22abc%22%7D%7D",
+    )
+    .unwrap();
+    assert_eq!(request.query, "");
+    assert!(request.extensions.contains_key("persistedQuery"));
+  }
+
+  #[test]
+  fn rejects_invalid_json() {
+    assert!(parse_request("query=%7B%20ping%20%7D&variables=%7Bid").unwrap_err().contains("variables"));
+    assert!(parse_request("query=%7B%20ping%20%7D&extensions=1").unwrap_err().contains("extensions"));
+  }
+
+  struct Query;
+
+  #[Object]
+  impl Query {
+    async fn value(&self) -> i32 {
+      1
+    }
+  }
+
+  struct Mutation;
+
+  #[Object]
+  impl Mutation {
+    async fn update(&self) -> i32 {
+      2
+    }
+  }
+
+  fn schema() -> Schema<Query, Mutation, EmptySubscription> {
+    Schema::build(Query, Mutation, EmptySubscription).extension(QueryOnly).finish()
+  }
+
+  #[tokio::test]
+  async fn executes_queries_over_get() {
+    let response = schema().execute(parse_request("query=%7B%20value%20%7D").unwrap()).await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+  }
+
+  #[tokio::test]
+  async fn rejects_mutations_over_get() {
+    let response = schema().execute(parse_request("query=mutation%20%7B%20update%20%7D").unwrap()).await;
+    assert!(response.errors[0].message.contains("must be sent with POST"));
+  }
+
+  #[tokio::test]
+  async fn rejects_mutations_selected_by_operation_name() {
+    let request = parse_request("query=query%20A%20%7B%20value%20%7D%20mutation%20B%20%7B%20update%20%7D&operationName=B");
+    let response = schema().execute(request.unwrap()).await;
+    assert!(response.errors[0].message.contains("must be sent with POST"));
+  }
+
+  #[tokio::test]
+  async fn allows_mutations_over_post() {
+    let response = schema().execute(Request::new("mutation { update }")).await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+  }
+}
@@ -0,0 +1,328 @@
+use std::{
+  collections::{HashMap, HashSet},
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+  },
+};
+
+use async_graphql::{
+  extensions::{
+    Extension,
+    ExtensionContext,
+    ExtensionFactory,
+    NextParseQuery,
+    NextValidation,
+  },
+  parser::types::{ExecutableDocument, Selection, SelectionSet},
+  ErrorExtensionValues,
+  ServerError,
+  ServerResult,
+  ValidationResult,
+  Variables,
+};
+
+use crate::config::LimitsConfig;
+use crate::pagination::MAX_PAGE_SIZE;
+
+// 複雑度の計算はリクエストの文脈を持たないので、起動時に設定のページサイズを入れておく
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(5);
+
+pub fn set_page_size(page_size: i32) {
+  PAGE_SIZE.store(page_size as usize, Ordering::Relaxed);
+}
+
+// ページ単位で返す一覧の件数
+pub fn page_size() -> usize {
+  PAGE_SIZE.load(Ordering::Relaxed)
+}
+
+// first / last が指定されていればその件数、なければ 1ページ分の件数
+pub fn list_size(first: Option<i32>, last: Option<i32>) -> usize {
+  match first.or(last) {
+    Some(size) => (size.max(0) as usize).min(MAX_PAGE_SIZE),
+    None => page_size(),
+  }
+}
+
+fn limit_error(code: &str, message: String) -> ServerError {
+  let mut extensions = ErrorExtensionValues::default();
+  extensions.set("code", code);
+  let mut error = ServerError::new(message, None);
+  error.extensions = Some(extensions);
+  error
+}
+
+// 深さと複雑度は async-graphql の検証結果を使い、エイリアスとルートのフィールド数は自前で数える
+pub struct QueryLimits {
+  config: Arc<LimitsConfig>,
+}
+
+impl QueryLimits {
+  pub fn new(config: LimitsConfig) -> Self {
+    QueryLimits { config: Arc::new(config) }
+  }
+}
+
+impl ExtensionFactory for QueryLimits {
+  fn create(&self) -> Arc<dyn Extension> {
+    Arc::new(QueryLimitsExtension {
+      config: self.config.clone(),
+      introspection: AtomicBool::new(false),
+    })
+  }
+}
+
+struct QueryLimitsExtension {
+  config: Arc<LimitsConfig>,
+  // playground などのイントロスペクションは深くなるので、複雑度の制限から外し、深さは別の上限で見る
+  introspection: AtomicBool,
+}
+
+#[async_trait::async_trait]
+impl Extension for QueryLimitsExtension {
+  async fn parse_query(
+    &self,
+    ctx: &ExtensionContext<'_>,
+    query: &str,
+    variables: &Variables,
+    next: NextParseQuery<'_>,
+  ) -> ServerResult<ExecutableDocument> {
+    let document = next.run(ctx, query, variables).await?;
+
+    let mut root_fields = Vec::new();
+    let mut aliases = 0;
+    let mut fragments = HashMap::new();
+    for (_, operation) in document.operations.iter() {
+      let selection_set = &operation.node.selection_set.node;
+      collect_root_fields(&document, selection_set, &mut root_fields, &mut HashSet::new());
+      aliases += count_aliases(&document, selection_set, &mut fragments);
+    }
+
+    if root_fields.len() > self.config.max_root_fields {
+      return Err(limit_error(
+        "TOO_MANY_ROOT_FIELDS",
+        format!("query has {} root fields, limit is {}", root_fields.len(), self.config.max_root_fields),
+      ));
+    }
+    if aliases > self.config.max_aliases {
+      return Err(limit_error(
+        "TOO_MANY_ALIASES",
+        format!("query has {} aliases, limit is {}", aliases, self.config.max_aliases),
+      ));
+    }
+
+    let introspection = !root_fields.is_empty() && root_fields.iter().all(|name| name.starts_with("__"));
+    self.introspection.store(introspection, Ordering::Relaxed);
+    Ok(document)
+  }
+
+  async fn validation(
+    &self,
+    ctx: &ExtensionContext<'_>,
+    next: NextValidation<'_>,
+  ) -> Result<ValidationResult, Vec<ServerError>> {
+    let result = next.run(ctx).await?;
+    let introspection = self.introspection.load(Ordering::Relaxed);
+
+    let max_depth = match introspection {
+      true => self.config.max_introspection_depth,
+      false => self.config.max_depth,
+    };
+    if result.depth > max_depth {
+      return Err(vec![limit_error(
+        "MAX_DEPTH_EXCEEDED",
+        format!("query depth is {}, limit is {}", result.depth, max_depth),
+      )]);
+    }
+    if !introspection && result.complexity > self.config.max_complexity {
+      return Err(vec![limit_error(
+        "MAX_COMPLEXITY_EXCEEDED",
+        format!("query complexity is {}, limit is {}", result.complexity, self.config.max_complexity),
+      )]);
+    }
+    Ok(result)
+  }
+}
+
+// フラグメントの中のフィールドもルートのフィールドとして数える
+// 検証前なので循環したフラグメントもありうる。同じフラグメントは一度しか辿らない
+fn collect_root_fields(
+  document: &ExecutableDocument,
+  selection_set: &SelectionSet,
+  names: &mut Vec<String>,
+  visited: &mut HashSet<String>,
+) {
+  for selection in &selection_set.items {
+    match &selection.node {
+      Selection::Field(field) => names.push(field.node.name.node.to_string()),
+      Selection::InlineFragment(fragment) => {
+        collect_root_fields(document, &fragment.node.selection_set.node, names, visited)
+      },
+      Selection::FragmentSpread(spread) => {
+        let name = &spread.node.fragment_name.node;
+        if !visited.insert(name.to_string()) {
+          continue;
+        }
+        if let Some(fragment) = document.fragments.get(name) {
+          collect_root_fields(document, &fragment.node.selection_set.node, names, visited);
+        }
+      },
+    }
+  }
+}
+
+// フラグメントは使われた箇所ごとに展開して数える
+// 同じフラグメントの結果は使い回し、循環している場合は 0 とする (検証で弾かれる)
+fn count_aliases(
+  document: &ExecutableDocument,
+  selection_set: &SelectionSet,
+  fragments: &mut HashMap<String, Option<usize>>,
+) -> usize {
+  let mut count: usize = 0;
+  for selection in &selection_set.items {
+    let aliases = match &selection.node {
+      Selection::Field(field) => {
+        let alias = if field.node.alias.is_some() { 1 } else { 0 };
+        count_aliases(document, &field.node.selection_set.node, fragments).saturating_add(alias)
+      },
+      Selection::InlineFragment(fragment) => count_aliases(document, &fragment.node.selection_set.node, fragments),
+      Selection::FragmentSpread(spread) => {
+        let name = spread.node.fragment_name.node.to_string();
+        match fragments.get(&name) {
+          Some(Some(count)) => *count,
+          // 展開中 = 循環している
+          Some(None) => 0,
+          None => {
+            fragments.insert(name.clone(), None);
+            let count = match document.fragments.get(&spread.node.fragment_name.node) {
+              Some(fragment) => count_aliases(document, &fragment.node.selection_set.node, fragments),
+              None => 0,
+            };
+            fragments.insert(name, Some(count));
+            count
+          },
+        }
+      },
+    };
+    // フラグメントを入れ子にすると指数的に増えるので溢れないようにする
+    count = count.saturating_add(aliases);
+  }
+  count
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use async_graphql::{EmptyMutation, EmptySubscription, Object, Schema};
+
+  struct Query;
+
+  #[Object]
+  impl Query {
+    async fn value(&self) -> i32 {
+      1
+    }
+
+    async fn node(&self) -> Node {
+      Node
+    }
+  }
+
+  struct Node;
+
+  #[Object]
+  impl Node {
+    async fn value(&self) -> i32 {
+      1
+    }
+
+    async fn child(&self) -> Node {
+      Node
+    }
+  }
+
+  async fn errors(query: &str) -> Vec<String> {
+    let config = LimitsConfig {
+      max_depth: 3,
+      max_complexity: 1000,
+      max_aliases: 2,
+      max_root_fields: 3,
+      max_introspection_depth: 6,
+    };
+    let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+      .extension(QueryLimits::new(config))
+      .finish();
+    let response = schema.execute(query).await;
+    response.errors.into_iter().map(|e| e.message).collect()
+  }
+
+  async fn assert_ok(query: &str) {
+    let errors = errors(query).await;
+    assert!(errors.is_empty(), "{}: {:?}", query, errors);
+  }
+
+  async fn assert_rejected(query: &str, message: &str) {
+    let errors = errors(query).await;
+    assert!(errors.iter().any(|e| e.contains(message)), "{}: {:?}", query, errors);
+  }
+
+  #[tokio::test]
+  async fn root_fields() {
+    assert_ok("{ value node { value } __typename }").await;
+    assert_rejected("{ value value node { value } __typename }", "root fields").await;
+  }
+
+  #[tokio::test]
+  async fn root_fields_in_fragments() {
+    assert_ok("{ ...F } fragment F on Query { value node { value } }").await;
+    assert_rejected("{ value ...F } fragment F on Query { ... on Query { value node { value } } __typename }", "root fields").await;
+  }
+
+  #[tokio::test]
+  async fn root_fields_across_operations() {
+    assert_rejected("query A { value node { value } } query B { value __typename }", "root fields").await;
+  }
+
+  #[tokio::test]
+  async fn aliases() {
+    assert_ok("{ a: value node { b: value } }").await;
+    assert_rejected("{ a: value node { b: value c: value } }", "aliases").await;
+  }
+
+  #[tokio::test]
+  async fn aliases_in_fragments_are_counted_per_spread() {
+    assert_ok("{ node { ...F } } fragment F on Node { a: value }").await;
+    assert_rejected("{ node { ...F child { ...F ...F } } } fragment F on Node { a: value }", "aliases").await;
+  }
+
+  #[tokio::test]
+  async fn depth() {
+    assert_ok("{ node { value } }").await;
+    assert_rejected("{ node { child { child { child { child { value } } } } } }", "query depth").await;
+  }
+
+  #[tokio::test]
+  async fn introspection_has_its_own_depth_limit() {
+    assert_ok("{ __schema { types { fields { type { name } } } } }").await;
+    assert_rejected(
+      "{ __schema { types { fields { type { ofType { ofType { ofType { ofType { name } } } } } } } } }",
+      "query depth",
+    )
+    .await;
+  }
+
+  #[tokio::test]
+  async fn introspection_through_fragments_has_its_own_depth_limit() {
+    assert_rejected(
+      "{ ...F } fragment F on Query { __schema { types { fields { type { ofType { ofType { ofType { ofType { name } } } } } } } } }",
+      "query depth",
+    )
+    .await;
+  }
+
+  #[tokio::test]
+  async fn introspection_mixed_with_fields_is_not_exempt() {
+    assert_rejected("{ __schema { types { fields { type { name } } } } value }", "query depth").await;
+  }
+}
@@ -0,0 +1,50 @@
+use sqlx::mysql::MySqlPool;
+use std::collections::HashMap;
+
+use async_graphql::dataloader::Loader;
+
+use crate::categories::{get_categories_by_ids, Category};
+use crate::resolvers::BlogError;
+use crate::tags::{get_tags_by_post_ids, Tag};
+
+// 同時に要求されたキーをまとめて、IN (...) の1クエリで取得する
+// キャッシュは持たせていないので、Schema の data に一つ登録して使い回せる
+
+// category id -> Category
+pub struct CategoryLoader(MySqlPool);
+
+impl CategoryLoader {
+  pub fn new(pool: MySqlPool) -> Self {
+    CategoryLoader(pool)
+  }
+}
+
+#[async_trait::async_trait]
+impl Loader<i32> for CategoryLoader {
+  type Value = Category;
+  type Error = BlogError;
+
+  async fn load(&self, keys: &[i32]) -> Result<HashMap<i32, Self::Value>, Self::Error> {
+    let categories = get_categories_by_ids(&self.0, keys).await?;
+    Ok(categories.into_iter().map(|category| (category.id, category)).collect())
+  }
+}
+
+// post id -> tags of the post
+pub struct TagsLoader(MySqlPool);
+
+impl TagsLoader {
+  pub fn new(pool: MySqlPool) -> Self {
+    TagsLoader(pool)
+  }
+}
+
+#[async_trait::async_trait]
+impl Loader<i32> for TagsLoader {
+  type Value = Vec<Tag>;
+  type Error = BlogError;
+
+  async fn load(&self, keys: &[i32]) -> Result<HashMap<i32, Self::Value>, Self::Error> {
+    get_tags_by_post_ids(&self.0, keys).await
+  }
+}
@@ -1,64 +1,355 @@
 #[macro_use]
 extern crate thiserror;
 
+mod auth;
+mod cache;
+mod categories;
+mod config;
+mod feeds;
+mod filter;
+mod health;
+mod http_cache;
+mod http_get;
+mod limits;
+mod loaders;
+mod markdown;
+mod metrics;
+mod mutations;
+mod pagination;
+mod persisted;
+mod preview;
+mod rate_limit;
 mod resolvers;
+mod search;
+mod sitemap;
+mod subscriptions;
+mod tags;
+mod telemetry;
 
 use axum::{
-    extract::Extension,
-    http::{HeaderValue, Method, StatusCode},
+    extract::{ws::WebSocketUpgrade, ConnectInfo, Extension, RawQuery},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware,
     response::{Html, IntoResponse},
     routing::get,
     Json, 
     Router, handler::Handler,
 };
 use async_graphql::{
-    http::{playground_source, GraphQLPlaygroundConfig},
-    EmptyMutation,
-    EmptySubscription,
+    dataloader::DataLoader,
+    extensions::Tracing,
+    http::{playground_source, GraphQLPlaygroundConfig, ALL_WEBSOCKET_PROTOCOLS},
+    Data,
     Request,
-    Response,
     Schema,
+    ServerError,
 };
-use std::net::SocketAddr;
-use tower_http::cors::CorsLayer;
+use async_graphql_axum::{GraphQLProtocol, GraphQLWebSocket};
+use std::{future, net::SocketAddr, sync::Arc, time::{Duration, Instant}};
+use tokio::sync::oneshot;
+use tower_http::{
+    cors::{Any, CorsLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::Instrument;
+use auth::{AdminToken, JwtVerifier, Viewer};
+use cache::ResolverCache;
+use config::Config;
+use health::Readiness;
+use http_cache::NoStore;
+use http_get::QueryOnly;
+use limits::QueryLimits;
+use loaders::{CategoryLoader, TagsLoader};
+use markdown::MarkdownRenderer;
+use mutations::MutationRoot;
+use persisted::PersistedQueries;
+use rate_limit::{RateLimit, RateLimiter};
 use resolvers::QueryRoot;
+use subscriptions::{PostEvents, SubscriptionOnly, SubscriptionRoot, WebSocketRequest};
+
+pub type BlogSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
 
-pub type BlogSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+// Viewer は auth::authenticate で載せたもの。mutation の認可は AdminGuard で行う
+async fn execute(
+    schema: &BlogSchema,
+    viewer: Viewer,
+    limiter: &RateLimiter,
+    peer: SocketAddr,
+    headers: &HeaderMap,
+    req: Request,
+) -> axum::response::Response {
+    let started = Instant::now();
+    let operation = req.operation_name.clone();
+    let authenticated = viewer.is_authenticated();
+    let limit = limiter.prepare(peer.ip(), headers, operation.clone());
+    let no_store = Arc::new(NoStore::default());
+    let span = tracing::info_span!("graphql", operation = operation.as_deref().unwrap_or(""));
+    let req = req.data(viewer).data(limit.clone()).data(no_store.clone());
+    let response = schema.execute(req).instrument(span).await;
+    metrics::record_graphql(operation.as_deref(), response.is_ok(), started);
+    let mut response = http_cache::graphql_response(headers, response, authenticated, no_store.is_marked());
+    limit.apply(&mut response);
+    response
+}
 
-async fn graphql_handler(schema: Extension<BlogSchema>, req: Json<Request>) -> Json<Response> {
-    schema.execute(req.0).await.into()
+async fn graphql_handler(
+    schema: Extension<BlogSchema>,
+    viewer: Extension<Viewer>,
+    limiter: Extension<Arc<RateLimiter>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    req: Json<Request>,
+) -> axum::response::Response {
+    execute(&schema, viewer.0, &limiter, peer, &headers, req.0).await
+}
+
+// GET /?query=... は query だけ実行する。CDN でキャッシュできるように
+// ブラウザ (Accept: text/html) やクエリ文字列のないアクセスには playground を返す
+async fn graphql_get_handler(
+    schema: Extension<BlogSchema>,
+    viewer: Extension<Viewer>,
+    limiter: Extension<Arc<RateLimiter>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    RawQuery(query_string): RawQuery,
+) -> axum::response::Response {
+    let mut response = match query_string {
+        Some(query_string) if !query_string.is_empty() && !http_get::wants_html(&headers) => {
+            match http_get::parse_request(&query_string) {
+                Ok(req) => execute(&schema, viewer.0, &limiter, peer, &headers, req).await,
+                // POST と同じく GraphQL の形式でエラーを返す
+                Err(e) => {
+                    let errors = async_graphql::Response::from_errors(vec![ServerError::new(e, None)]);
+                    (StatusCode::BAD_REQUEST, Json(errors)).into_response()
+                },
+            }
+        },
+        _ => graphql_playground().await.into_response(),
+    };
+    // 同じ URL でも Accept によって playground を返すことがある
+    response.headers_mut().append(header::VARY, HeaderValue::from_static("Accept"));
+    response
+}
+
+// graphql-ws / graphql-transport-ws は Sec-WebSocket-Protocol を見て自動で切り替わる
+// 接続ごとにレート制限の状態を載せて、購読の開始も query として数える
+async fn graphql_ws_handler(
+    schema: Extension<BlogSchema>,
+    limiter: Extension<Arc<RateLimiter>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    protocol: GraphQLProtocol,
+    upgrade: WebSocketUpgrade,
+) -> axum::response::Response {
+    let mut data = Data::default();
+    data.insert(limiter.prepare(peer.ip(), &headers, None));
+    data.insert(WebSocketRequest);
+    let schema = schema.0;
+    upgrade
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |stream| GraphQLWebSocket::new(stream, schema, protocol).with_data(data).serve())
 }
 
 async fn graphql_playground() -> impl IntoResponse {
-    Html(playground_source(GraphQLPlaygroundConfig::new("/")))
+    Html(playground_source(GraphQLPlaygroundConfig::new("/").subscription_endpoint("/ws")))
 }
 
 async fn notfound_handler() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "not found")
 }
 
+// SIGTERM (デプロイ時) か SIGINT (Ctrl-C) を待つ
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
+    }
+}
+
 #[tokio::main]
 async fn main() {
+    // 設定が不正な場合は起動しない
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
+    telemetry::init(&config.log);
+
+    // blogapp_post は Django 側のテーブルでもあり、インデックスの追加などはテーブルをロックする
+    // 起動時には適用せず、デプロイ手順として `api migrate` を明示的に実行する
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        let pool = resolvers::pool(&config.database).await.expect("failed to connect database");
+        health::MIGRATOR.run(&pool).await.expect("failed to run migrations");
+        tracing::info!("migrations applied");
+        pool.close().await;
+        return;
+    }
+
     let server = async {
-        let schema = Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
-        .finish();
+        // コネクションプールは起動時に一度だけ作成して、全リクエストで使い回す
+        // マイグレーションが未適用の場合は /readyz が 503 を返す
+        let pool = resolvers::pool(&config.database).await.expect("failed to connect database");
+
+        let verifier = JwtVerifier::from_config(&config.auth).expect("invalid JWT configuration");
+        let admin_token = AdminToken::from_config(&config.auth);
+        if admin_token.is_some() {
+            tracing::warn!("ADMIN_TOKEN is deprecated, issue JWTs with the admin role instead");
+        }
+        let renderer = Arc::new(MarkdownRenderer::new());
+        let readiness = Arc::new(Readiness::new());
+        let cache = Arc::new(ResolverCache::new(&config.cache));
+        let limiter = Arc::new(RateLimiter::new(&config.rate_limit));
+        limits::set_page_size(config.posts.page_size);
+
+        let mut builder = Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(DataLoader::new(CategoryLoader::new(pool.clone()), tokio::spawn))
+        .data(DataLoader::new(TagsLoader::new(pool.clone()), tokio::spawn))
+        .data(pool.clone())
+        .data(PostEvents::new())
+        .data(renderer.clone())
+        .data(config.clone())
+        .data(readiness.clone())
+        .data(cache.clone())
+        // operation / parse / validate / resolver ごとの span を出す
+        .extension(Tracing);
+        // クライアントごとに query と mutation の回数を制限する
+        // 先に登録した拡張ほど外側で動くので、後の拡張で弾かれたリクエストも数えられるように先に置く
+        if config.rate_limit.enabled {
+            builder = builder.extension(RateLimit::new(limiter.clone()));
+        }
+        builder = builder
+        .extension(QueryLimits::new(config.limits.clone()))
+        .extension(QueryOnly)
+        .extension(SubscriptionOnly);
+        // クエリ本文の代わりに sha256 ハッシュだけで実行できるようにする
+        if config.persisted_queries.enabled {
+            builder = builder.extension(PersistedQueries::new(config.persisted_queries.capacity));
+        }
+        // preview.secret が未設定の場合、previewPost は常に失敗する
+        if let Some(secret) = preview::preview_secret(&config.preview) {
+            builder = builder.data(secret);
+        }
+        let schema = builder.finish();
+
+        let origins = &config.server.cors_origins;
+        let cors = match origins.iter().any(|origin| origin == "*") {
+            true => CorsLayer::new().allow_origin(Any),
+            // Config::validate で HeaderValue として正しいことは確認済み
+            false => CorsLayer::new().allow_origin(
+                origins.iter().map(|origin| origin.parse::<HeaderValue>().unwrap()).collect::<Vec<_>>(),
+            ),
+        };
 
-        let app = Router::new().route("/", get(graphql_playground).post(graphql_handler))
+        // 不正なトークンを 401 にするのは GraphQL だけ。フィードやヘルスチェックは Authorization を見ない
+        let graphql = Router::new().route("/", get(graphql_get_handler).post(graphql_handler))
+        .route("/ws", get(graphql_ws_handler))
+        .route_layer(middleware::from_fn(auth::authenticate));
+
+        let app = Router::new()
+        .route("/feed.xml", get(feeds::rss_handler))
+        .route("/atom.xml", get(feeds::atom_handler))
+        .route("/categories/:category/feed.xml", get(feeds::category_rss_handler))
+        .route("/categories/:category/atom.xml", get(feeds::category_atom_handler))
+        .route("/sitemap.xml", get(sitemap::sitemap_handler))
+        .route("/sitemaps/:file", get(sitemap::sitemap_file_handler))
+        .route("/healthz", get(health::healthz_handler))
+        .route("/readyz", get(health::readyz_handler))
+        .route("/metrics", get(metrics::metrics_handler))
+        .merge(graphql)
         .layer(
-            CorsLayer::new()
-                // 一旦現段階で想定してるのはブログだけ       
-                .allow_origin("*".parse::<HeaderValue>().unwrap())
-                .allow_methods([Method::GET, Method::POST, Method::OPTIONS]),
+            cors
+                .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+                .allow_headers([
+                    header::CONTENT_TYPE,
+                    header::AUTHORIZATION,
+                    HeaderName::from_static(rate_limit::API_KEY),
+                ])
+                .expose_headers([
+                    header::RETRY_AFTER,
+                    HeaderName::from_static(rate_limit::RATE_LIMIT_LIMIT),
+                    HeaderName::from_static(rate_limit::RATE_LIMIT_REMAINING),
+                    HeaderName::from_static(rate_limit::RATE_LIMIT_RESET),
+                ]),
         )
-        .layer(Extension(schema));
+        .layer(middleware::from_fn(metrics::track))
+        .layer(Extension(schema))
+        .layer(Extension(pool.clone()))
+        .layer(Extension(config.clone()))
+        .layer(Extension(renderer))
+        .layer(Extension(readiness.clone()))
+        .layer(Extension(cache))
+        .layer(Extension(limiter));
+
+        // auth.jwt_algorithm が未設定の場合、ADMIN_TOKEN 以外のトークンは匿名として扱う
+        let app = match verifier {
+            Some(verifier) => app.layer(Extension(Arc::new(verifier))),
+            None => app,
+        };
+        let app = match admin_token {
+            Some(admin_token) => app.layer(Extension(Arc::new(admin_token))),
+            None => app,
+        };
+
+        // X-Request-Id を付けてから span を作る。レスポンスにも同じ ID を返す
+        let app = app
+            .layer(TraceLayer::new_for_http().make_span_with(telemetry::make_span))
+            .layer(PropagateRequestIdLayer::x_request_id())
+            .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
 
         let app = app.fallback(notfound_handler.into_service());
 
-        let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
-        axum::Server::bind(&addr)
-            .serve(app.into_make_service())
-            .await
-            .unwrap();
+        // host は Config::validate で IP アドレスであることを確認済み
+        let addr = SocketAddr::new(config.server.host.parse().unwrap(), config.server.port);
+        tracing::info!(%addr, "listening");
+        let shutdown_delay = Duration::from_secs(config.server.shutdown_delay);
+        let drain_timeout = Duration::from_secs(config.server.drain_timeout);
+
+        // シグナルを受け取ったら readiness を落として、新規接続の受付を止める
+        let (draining_tx, draining_rx) = oneshot::channel();
+        let server = axum::Server::bind(&addr)
+            // レート制限で接続元の IP アドレスを使う
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async move {
+                shutdown_signal().await;
+                tracing::info!("shutdown signal received, draining");
+                readiness.start_draining();
+                tokio::time::sleep(shutdown_delay).await;
+                let _ = draining_tx.send(());
+            });
+
+        // 処理中のリクエストは drain_timeout まで待つ
+        // WebSocket の購読は切れないので、残っていればここで打ち切る
+        let drain_deadline = async {
+            match draining_rx.await {
+                Ok(()) => tokio::time::sleep(drain_timeout).await,
+                Err(_) => future::pending().await,
+            }
+        };
+        tokio::select! {
+            result = server => result.unwrap(),
+            _ = drain_deadline => tracing::warn!("drain timeout exceeded, closing remaining connections"),
+        }
+
+        // 全てのコネクションを返却してから閉じる
+        pool.close().await;
     };
 
     tokio::join!(server);
@@ -0,0 +1,207 @@
+use chrono::{DateTime, Utc};
+use lru::LruCache;
+use pulldown_cmark::{html, CodeBlockKind, Event, Options, Parser, Tag};
+use std::{
+  collections::hash_map::DefaultHasher,
+  hash::{Hash, Hasher},
+  sync::{Arc, Mutex},
+};
+use syntect::{
+  html::{ClassStyle, ClassedHTMLGenerator},
+  parsing::SyntaxSet,
+  util::LinesWithEndings,
+};
+
+use crate::resolvers::BlogError;
+
+const CACHE_CAPACITY: usize = 512;
+
+// updated_at を更新せずに本文が書き換えられた場合に備えて、本文のハッシュもキーに含める
+#[derive(Hash, PartialEq, Eq)]
+struct CacheKey {
+  id: i32,
+  updated_at: DateTime<Utc>,
+  digest: u64,
+}
+
+// Markdown を HTML に変換して、結果を LRU でキャッシュする
+// SyntaxSet の読み込みが重いので、起動時に一つ作って Schema の data に登録する
+pub struct MarkdownRenderer {
+  syntax_set: Arc<SyntaxSet>,
+  cache: Mutex<LruCache<CacheKey, Arc<String>>>,
+}
+
+impl MarkdownRenderer {
+  pub fn new() -> Self {
+    MarkdownRenderer {
+      syntax_set: Arc::new(SyntaxSet::load_defaults_newlines()),
+      cache: Mutex::new(LruCache::new(CACHE_CAPACITY)),
+    }
+  }
+
+  pub async fn render(&self, id: i32, updated_at: DateTime<Utc>, markdown: &str) -> Result<Arc<String>, BlogError> {
+    let mut hasher = DefaultHasher::new();
+    markdown.hash(&mut hasher);
+    let key = CacheKey {
+      id,
+      updated_at,
+      digest: hasher.finish(),
+    };
+
+    if let Some(html) = self.cache.lock().unwrap().get(&key) {
+      return Ok(html.clone());
+    }
+    // ハイライトとサニタイズは長い投稿だと重いので、tokio のワーカーを塞がないよう別スレッドで変換する
+    // 変換中はロックを持たない
+    let syntax_set = self.syntax_set.clone();
+    let markdown = markdown.to_string();
+    let html = match tokio::task::spawn_blocking(move || to_html(&syntax_set, &markdown)).await {
+      Ok(html) => Arc::new(html),
+      Err(e) => return Err(BlogError::ServerError(e.to_string())),
+    };
+    self.cache.lock().unwrap().put(key, html.clone());
+    Ok(html)
+  }
+}
+
+impl Default for MarkdownRenderer {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+fn to_html(syntax_set: &SyntaxSet, markdown: &str) -> String {
+  let mut options = Options::empty();
+  options.insert(Options::ENABLE_TABLES);
+  options.insert(Options::ENABLE_FOOTNOTES);
+  options.insert(Options::ENABLE_TASKLISTS);
+  options.insert(Options::ENABLE_STRIKETHROUGH);
+
+  // フェンス付きコードブロックだけ取り出して、ハイライト済みの HTML に置き換える
+  let mut events = Vec::new();
+  let mut code: Option<(String, String)> = None;
+  for event in Parser::new_ext(markdown, options) {
+    match event {
+      Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(lang))) => {
+        code = Some((lang.to_string(), String::new()));
+      },
+      Event::Text(text) if code.is_some() => {
+        if let Some((_, source)) = code.as_mut() {
+          source.push_str(&text);
+        }
+      },
+      Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(_))) => {
+        if let Some((lang, source)) = code.take() {
+          events.push(Event::Html(highlight(syntax_set, &lang, &source).into()));
+        }
+      },
+      event => events.push(event),
+    }
+  }
+
+  let mut unsafe_html = String::new();
+  html::push_html(&mut unsafe_html, events.into_iter());
+  sanitize(&unsafe_html)
+}
+
+fn highlight(syntax_set: &SyntaxSet, lang: &str, source: &str) -> String {
+  // ```rust,ignore のような info string は先頭の言語名だけ使う
+  let token = lang.split(|c: char| c == ',' || c.is_whitespace()).next().unwrap_or("");
+  let syntax = syntax_set
+    .find_syntax_by_token(token)
+    .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+
+  let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, syntax_set, ClassStyle::Spaced);
+  for line in LinesWithEndings::from(source) {
+    if generator.parse_html_for_line_which_includes_newline(line).is_err() {
+      // ハイライトに失敗したらエスケープしただけのコードを出す
+      return format!("<pre><code>{}</code></pre>", ammonia::clean_text(source));
+    }
+  }
+  format!("<pre class=\"code\"><code class=\"language-{}\">{}</code></pre>", ammonia::clean_text(token), generator.finalize())
+}
+
+// 本文に生の HTML が書かれていても script などは落とす
+// ハイライトのクラス、脚注、タスクリストのチェックボックスは残す
+fn sanitize(html: &str) -> String {
+  ammonia::Builder::default()
+    .add_tags(&["input"])
+    .add_tag_attributes("input", &["type", "checked", "disabled"])
+    .add_tag_attributes("div", &["id"])
+    .add_generic_attributes(&["class"])
+    .clean(html)
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn render(markdown: &str) -> String {
+    to_html(&SyntaxSet::load_defaults_newlines(), markdown)
+  }
+
+  #[test]
+  fn script_tags_are_stripped() {
+    let html = render("hello\n\n<script>alert('xss')</script>\n\nworld");
+    assert!(!html.contains("<script"));
+    assert!(!html.contains("alert"));
+    assert!(html.contains("<p>hello</p>"));
+    assert!(html.contains("<p>world</p>"));
+  }
+
+  #[test]
+  fn event_handler_attributes_are_stripped() {
+    let html = render("<img src=\"a.png\" onerror=\"alert(1)\"> <a href=\"/\" onclick=\"alert(2)\">link</a>");
+    assert!(!html.contains("onerror"));
+    assert!(!html.contains("onclick"));
+    assert!(!html.contains("alert"));
+    assert!(html.contains("src=\"a.png\""));
+  }
+
+  #[test]
+  fn javascript_links_are_stripped() {
+    let html = render("[link](javascript:alert(1))");
+    assert!(!html.contains("javascript:"));
+  }
+
+  #[test]
+  fn fenced_code_is_highlighted() {
+    let html = render("```rust\nfn main() {}\n```");
+    assert!(html.contains("<pre class=\"code\"><code class=\"language-rust\">"));
+    // ハイライトされたトークンは class 付きの span になる
+    assert!(html.contains("<span class=\""));
+    assert!(html.contains("main"));
+  }
+
+  #[test]
+  fn info_string_uses_only_the_language() {
+    let html = render("```rust,ignore\nlet x = 1;\n```");
+    assert!(html.contains("class=\"language-rust\""));
+  }
+
+  #[test]
+  fn unknown_language_is_escaped_as_plain_text() {
+    let html = render("```nosuchlang\n<b>bold</b>\n```");
+    assert!(html.contains("&lt;b&gt;bold&lt;/b&gt;"));
+    assert!(!html.contains("<b>"));
+  }
+
+  #[test]
+  fn unclosed_code_block_in_excerpt_is_closed() {
+    let html = render("intro\n\n```rust\nfn main() {");
+    assert!(html.contains("<p>intro</p>"));
+    assert!(html.trim_end().ends_with("</code></pre>"));
+  }
+
+  #[tokio::test]
+  async fn render_caches_by_id_updated_at_and_contents() {
+    let renderer = MarkdownRenderer::new();
+    let updated_at = Utc::now();
+    let first = renderer.render(1, updated_at, "# title").await.unwrap();
+    let cached = renderer.render(1, updated_at, "# title").await.unwrap();
+    assert!(Arc::ptr_eq(&first, &cached));
+    let changed = renderer.render(1, updated_at, "# other").await.unwrap();
+    assert!(changed.contains("other"));
+  }
+}
@@ -0,0 +1,294 @@
+use axum::{
+  extract::{Extension, MatchedPath},
+  http::{header, Request, StatusCode},
+  middleware::Next,
+  response::{IntoResponse, Response},
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+  Encoder,
+  HistogramOpts,
+  HistogramVec,
+  IntCounterVec,
+  IntGauge,
+  Opts,
+  Registry,
+  TextEncoder,
+};
+use sqlx::mysql::MySqlPool;
+use std::{
+  collections::HashSet,
+  sync::{Arc, Mutex},
+  time::Instant,
+};
+
+use crate::cache::ResolverCache;
+use crate::config::Config;
+use crate::resolvers::BlogError;
+
+pub struct Metrics {
+  registry: Registry,
+  http_requests: IntCounterVec,
+  http_duration: HistogramVec,
+  graphql_operations: IntCounterVec,
+  graphql_duration: HistogramVec,
+  resolver_errors: IntCounterVec,
+  db_errors: IntCounterVec,
+  pool_size: IntGauge,
+  pool_idle: IntGauge,
+  pool_max: IntGauge,
+  cache_requests: IntCounterVec,
+  cache_entries: IntGauge,
+  rate_limited: IntCounterVec,
+  operation_names: OperationNames,
+}
+
+// BlogError::extend のようにリクエストの文脈を持たない場所からも数えるので、プロセスで一つだけ持つ
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+impl Metrics {
+  fn new() -> Self {
+    let registry = Registry::new();
+
+    let http_requests = IntCounterVec::new(
+      Opts::new("http_requests_total", "HTTP requests by route and status"),
+      &["method", "route", "status"],
+    )
+    .unwrap();
+    let http_duration = HistogramVec::new(
+      HistogramOpts::new("http_request_duration_seconds", "HTTP request latency by route and status"),
+      &["method", "route", "status"],
+    )
+    .unwrap();
+    let graphql_operations = IntCounterVec::new(
+      Opts::new("graphql_operations_total", "GraphQL operations by operation name and result"),
+      &["operation", "result"],
+    )
+    .unwrap();
+    let graphql_duration = HistogramVec::new(
+      HistogramOpts::new("graphql_operation_duration_seconds", "GraphQL operation latency by operation name"),
+      &["operation"],
+    )
+    .unwrap();
+    let resolver_errors = IntCounterVec::new(
+      Opts::new("graphql_resolver_errors_total", "resolver errors by BlogError variant"),
+      &["error"],
+    )
+    .unwrap();
+    let db_errors = IntCounterVec::new(Opts::new("db_errors_total", "MySQL errors by kind"), &["kind"]).unwrap();
+    // sqlx 0.5 の Pool はコネクション待ちの数を公開していない (待ち行列は内部の semaphore で API がない)
+    // クエリは &MySqlPool をそのまま Executor として渡しているので、acquire を包んで数えることもできない
+    // 使用中の数は size - idle、待ちきれなかった数は db_errors_total{kind="pool_timed_out"} で見る
+    let pool_size = IntGauge::new("db_pool_connections", "open MySQL connections").unwrap();
+    let pool_idle = IntGauge::new("db_pool_idle_connections", "idle MySQL connections").unwrap();
+    let pool_max = IntGauge::new("db_pool_max_connections", "maximum MySQL connections").unwrap();
+
+    let cache_requests = IntCounterVec::new(
+      Opts::new("resolver_cache_requests_total", "resolver cache lookups by result"),
+      &["result"],
+    )
+    .unwrap();
+    let cache_entries = IntGauge::new("resolver_cache_entries", "entries in the resolver cache").unwrap();
+    let rate_limited = IntCounterVec::new(
+      Opts::new("graphql_rate_limited_total", "GraphQL requests rejected by the rate limiter by budget"),
+      &["budget"],
+    )
+    .unwrap();
+
+    registry.register(Box::new(http_requests.clone())).unwrap();
+    registry.register(Box::new(http_duration.clone())).unwrap();
+    registry.register(Box::new(graphql_operations.clone())).unwrap();
+    registry.register(Box::new(graphql_duration.clone())).unwrap();
+    registry.register(Box::new(resolver_errors.clone())).unwrap();
+    registry.register(Box::new(db_errors.clone())).unwrap();
+    registry.register(Box::new(pool_size.clone())).unwrap();
+    registry.register(Box::new(pool_idle.clone())).unwrap();
+    registry.register(Box::new(pool_max.clone())).unwrap();
+    registry.register(Box::new(cache_requests.clone())).unwrap();
+    registry.register(Box::new(cache_entries.clone())).unwrap();
+    registry.register(Box::new(rate_limited.clone())).unwrap();
+
+    Metrics {
+      registry,
+      http_requests,
+      http_duration,
+      graphql_operations,
+      graphql_duration,
+      resolver_errors,
+      db_errors,
+      pool_size,
+      pool_idle,
+      pool_max,
+      cache_requests,
+      cache_entries,
+      rate_limited,
+      operation_names: OperationNames::new(MAX_OPERATION_NAMES),
+    }
+  }
+}
+
+// operationName はクライアントが自由に付けられるので、そのままラベルにすると系列が際限なく増える
+const MAX_OPERATION_NAMES: usize = 100;
+const MAX_OPERATION_NAME_LENGTH: usize = 64;
+
+// 先着で上限までの名前だけをラベルにし、それ以降の新しい名前や GraphQL の名前として不正なものは other にまとめる
+struct OperationNames {
+  capacity: usize,
+  names: Mutex<HashSet<String>>,
+}
+
+impl OperationNames {
+  fn new(capacity: usize) -> Self {
+    OperationNames {
+      capacity,
+      names: Mutex::new(HashSet::new()),
+    }
+  }
+
+  fn label(&self, operation: Option<&str>) -> String {
+    // 名前のない operation はまとめて数える
+    let operation = match operation {
+      Some(operation) => operation,
+      None => return "anonymous".to_string(),
+    };
+    if !is_name(operation) {
+      return "other".to_string();
+    }
+    let mut names = self.names.lock().unwrap();
+    if names.contains(operation) {
+      return operation.to_string();
+    }
+    if names.len() >= self.capacity {
+      return "other".to_string();
+    }
+    names.insert(operation.to_string());
+    operation.to_string()
+  }
+}
+
+// /[_A-Za-z][_0-9A-Za-z]*/
+fn is_name(s: &str) -> bool {
+  let mut chars = s.chars();
+  match chars.next() {
+    Some(c) if c == '_' || c.is_ascii_alphabetic() => {},
+    _ => return false,
+  }
+  s.len() <= MAX_OPERATION_NAME_LENGTH && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+pub fn record_graphql(operation: Option<&str>, ok: bool, started: Instant) {
+  let operation = METRICS.operation_names.label(operation);
+  let result = if ok { "ok" } else { "error" };
+  METRICS.graphql_operations.with_label_values(&[&operation, result]).inc();
+  METRICS
+    .graphql_duration
+    .with_label_values(&[&operation])
+    .observe(started.elapsed().as_secs_f64());
+}
+
+pub fn record_error(err: &BlogError) {
+  METRICS.resolver_errors.with_label_values(&[err.kind()]).inc();
+}
+
+pub fn record_cache(hit: bool) {
+  let result = if hit { "hit" } else { "miss" };
+  METRICS.cache_requests.with_label_values(&[result]).inc();
+}
+
+pub fn record_rate_limited(budget: &str) {
+  METRICS.rate_limited.with_label_values(&[budget]).inc();
+}
+
+pub fn record_db_error(err: &sqlx::Error) {
+  let kind = match err {
+    sqlx::Error::Database(_) => "database",
+    sqlx::Error::Io(_) | sqlx::Error::Tls(_) => "connection",
+    sqlx::Error::PoolTimedOut => "pool_timed_out",
+    sqlx::Error::PoolClosed => "pool_closed",
+    sqlx::Error::RowNotFound => "row_not_found",
+    _ => "other",
+  };
+  METRICS.db_errors.with_label_values(&[kind]).inc();
+}
+
+/**
+ * middleware
+ */
+// ルートは実際のパスではなく `/categories/:category/feed.xml` のようなパターンで数える
+pub async fn track<B>(req: Request<B>, next: Next<B>) -> Response {
+  let started = Instant::now();
+  let method = req.method().to_string();
+  let route = match req.extensions().get::<MatchedPath>() {
+    Some(path) => path.as_str().to_string(),
+    None => "unmatched".to_string(),
+  };
+
+  let response = next.run(req).await;
+
+  let status = response.status().as_u16().to_string();
+  let labels = [method.as_str(), route.as_str(), status.as_str()];
+  METRICS.http_requests.with_label_values(&labels).inc();
+  METRICS
+    .http_duration
+    .with_label_values(&labels)
+    .observe(started.elapsed().as_secs_f64());
+  response
+}
+
+/**
+ * handlers
+ */
+pub async fn metrics_handler(
+  Extension(pool): Extension<MySqlPool>,
+  Extension(config): Extension<Arc<Config>>,
+  Extension(cache): Extension<Arc<ResolverCache>>,
+) -> Response {
+  // プールの状態はスクレイプのたびに読み直す
+  METRICS.pool_size.set(pool.size() as i64);
+  METRICS.pool_idle.set(pool.num_idle() as i64);
+  METRICS.pool_max.set(config.database.max_connections as i64);
+  METRICS.cache_entries.set(cache.entry_count() as i64);
+
+  let mut body = Vec::new();
+  let encoder = TextEncoder::new();
+  match encoder.encode(&METRICS.registry.gather(), &mut body) {
+    Ok(()) => ([(header::CONTENT_TYPE, encoder.format_type().to_string())], body).into_response(),
+    Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn anonymous_operations() {
+    let names = OperationNames::new(2);
+    assert_eq!(names.label(None), "anonymous");
+  }
+
+  #[test]
+  fn names_up_to_capacity() {
+    let names = OperationNames::new(2);
+    assert_eq!(names.label(Some("GetPosts")), "GetPosts");
+    assert_eq!(names.label(Some("GetPost")), "GetPost");
+    assert_eq!(names.label(Some("GetPosts")), "GetPosts");
+    assert_eq!(names.label(Some("Random1")), "other");
+    assert_eq!(names.label(Some("Random2")), "other");
+    // 一度ラベルにした名前は上限に達した後も使う
+    assert_eq!(names.label(Some("GetPost")), "GetPost");
+  }
+
+  #[test]
+  fn invalid_names() {
+    let names = OperationNames::new(10);
+    assert_eq!(names.label(Some("")), "other");
+    assert_eq!(names.label(Some("1st")), "other");
+    assert_eq!(names.label(Some("get-posts")), "other");
+    assert_eq!(names.label(Some("a\"} else {\"")), "other");
+    assert_eq!(names.label(Some(&"a".repeat(65))), "other");
+    assert_eq!(names.label(Some("_private")), "_private");
+    // 不正な名前は枠を使わない
+    assert_eq!(names.names.lock().unwrap().len(), 1);
+  }
+}
@@ -0,0 +1,438 @@
+use chrono::{DateTime, Utc};
+use sqlx::mysql::MySqlPool;
+use std::sync::Arc;
+use tracing::instrument;
+
+use async_graphql::{
+  Object,
+  Context,
+  InputObject,
+  FieldResult,
+  ResultExt,
+};
+
+use crate::auth::AdminGuard;
+use crate::cache::ResolverCache;
+use crate::config::Config;
+use crate::preview::{self, PreviewSecret, PreviewToken};
+use crate::resolvers::{get_post, BlogError, Post};
+use crate::subscriptions::{PostEvent, PostEvents};
+use crate::tags::set_post_tags;
+
+#[derive(InputObject)]
+pub struct CreatePostInput {
+  title: String,
+  /// name of the category
+  category: String,
+  contents: String,
+  /// defaults to now
+  pub_date: Option<DateTime<Utc>>,
+  #[graphql(default)]
+  open: bool,
+  /// names of the tags, created if missing
+  #[graphql(default)]
+  tags: Vec<String>,
+}
+
+// 指定されたフィールドだけ更新する
+#[derive(InputObject)]
+pub struct UpdatePostInput {
+  title: Option<String>,
+  /// name of the category
+  category: Option<String>,
+  contents: Option<String>,
+  pub_date: Option<DateTime<Utc>>,
+  open: Option<bool>,
+  /// replaces all tags of the post
+  tags: Option<Vec<String>>,
+}
+
+#[derive(sqlx::FromRow)]
+struct StoredPost {
+  title: String,
+  category_id: i32,
+  contents: Option<String>,
+  pub_date: DateTime<Utc>,
+  open: i8,
+}
+
+#[derive(sqlx::FromRow)]
+struct CategoryId {
+  id: i32,
+}
+
+pub struct MutationRoot;
+
+// 変更前後の公開状態を見て、subscription に流すイベントを決める
+// 非公開のままの投稿は外に流さない。非公開にした場合は下書きの本文が漏れないよう、変更前の投稿を Deleted として流す
+fn post_events(before: Option<&Post>, after: &Post) -> Vec<PostEvent> {
+  let was_open = matches!(before, Some(post) if post.open != 0);
+  match (before, after.open != 0) {
+    (Some(before), false) if was_open => vec![PostEvent::Deleted(before.clone())],
+    (_, true) if !was_open => vec![PostEvent::Published(after.clone()), PostEvent::Updated(after.clone())],
+    (_, true) => vec![PostEvent::Updated(after.clone())],
+    _ => vec![],
+  }
+}
+
+fn notify(ctx: &Context<'_>, before: Option<&Post>, post: &Post) {
+  if let Ok(events) = ctx.data::<PostEvents>() {
+    for event in post_events(before, post) {
+      events.send(event);
+    }
+  }
+}
+
+// 公開されていた投稿が削除された場合だけ postUpdated の購読者に知らせる
+fn notify_deleted(ctx: &Context<'_>, post: &Post) {
+  if let Ok(events) = ctx.data::<PostEvents>() {
+    if post.open != 0 {
+      events.send(PostEvent::Deleted(post.clone()));
+    }
+  }
+}
+
+// 投稿を変更したら、その投稿と一覧のキャッシュを捨てる
+fn invalidate(ctx: &Context<'_>, id: i32) {
+  if let Ok(cache) = ctx.data::<Arc<ResolverCache>>() {
+    cache.invalidate_post(id);
+  }
+}
+
+// 変更前の投稿。存在しなければ変更も失敗する
+async fn previous(pool: &MySqlPool, id: i32) -> Option<Post> {
+  get_post(pool, id).await.ok()
+}
+
+/**
+ * mutations
+ */
+#[Object]
+impl MutationRoot {
+  #[allow(non_snake_case)]
+  #[graphql(guard = "AdminGuard")]
+  async fn createPost(
+    &self,
+    ctx: &Context<'_>,
+    input: CreatePostInput,
+  ) -> FieldResult<Post> {
+    let pool = ctx.data::<MySqlPool>()?;
+    let post = create_post(pool, input).await.extend()?;
+    invalidate(ctx, post.id);
+    notify(ctx, None, &post);
+    Ok(post)
+  }
+
+  #[allow(non_snake_case)]
+  #[graphql(guard = "AdminGuard")]
+  async fn updatePost(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(desc = "id of the post")] id: i32,
+    input: UpdatePostInput,
+  ) -> FieldResult<Post> {
+    let pool = ctx.data::<MySqlPool>()?;
+    let before = previous(pool, id).await;
+    let post = update_post(pool, id, input).await.extend()?;
+    invalidate(ctx, id);
+    notify(ctx, before.as_ref(), &post);
+    Ok(post)
+  }
+
+  #[allow(non_snake_case)]
+  #[graphql(guard = "AdminGuard")]
+  async fn deletePost(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(desc = "id of the post")] id: i32,
+  ) -> FieldResult<Post> {
+    let pool = ctx.data::<MySqlPool>()?;
+    let post = delete_post(pool, id).await.extend()?;
+    invalidate(ctx, id);
+    notify_deleted(ctx, &post);
+    Ok(post)
+  }
+
+  #[allow(non_snake_case)]
+  #[graphql(guard = "AdminGuard")]
+  async fn publishPost(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(desc = "id of the post")] id: i32,
+  ) -> FieldResult<Post> {
+    let pool = ctx.data::<MySqlPool>()?;
+    let before = previous(pool, id).await;
+    let post = set_open(pool, id, true).await.extend()?;
+    invalidate(ctx, id);
+    notify(ctx, before.as_ref(), &post);
+    Ok(post)
+  }
+
+  #[allow(non_snake_case)]
+  #[graphql(guard = "AdminGuard")]
+  async fn unpublishPost(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(desc = "id of the post")] id: i32,
+  ) -> FieldResult<Post> {
+    let pool = ctx.data::<MySqlPool>()?;
+    let before = previous(pool, id).await;
+    let post = set_open(pool, id, false).await.extend()?;
+    invalidate(ctx, id);
+    notify(ctx, before.as_ref(), &post);
+    Ok(post)
+  }
+
+  // カテゴリを API の外で変更した場合などに、resolver のキャッシュを全て捨てる
+  #[allow(non_snake_case)]
+  #[graphql(guard = "AdminGuard")]
+  async fn invalidateCache(&self, ctx: &Context<'_>) -> FieldResult<bool> {
+    ctx.data::<Arc<ResolverCache>>()?.invalidate_all();
+    Ok(true)
+  }
+
+  // 下書きを共有するための期限付きトークンを発行する
+  #[allow(non_snake_case)]
+  #[graphql(guard = "AdminGuard")]
+  async fn createPreviewToken(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(desc = "id of the post")] id: i32,
+    #[graphql(desc = "lifetime of the token in seconds")] expires_in: Option<i64>,
+  ) -> FieldResult<PreviewToken> {
+    let pool = ctx.data::<MySqlPool>()?;
+    let secret = match ctx.data_opt::<PreviewSecret>() {
+      Some(secret) => secret,
+      None => return Err(BlogError::ServerError("preview.secret (PREVIEW_SECRET) is not set".to_string())).extend(),
+    };
+    let config = ctx.data::<Arc<Config>>()?;
+    let expires_in = preview::expires_in(&config.preview, expires_in).extend()?;
+    get_post(pool, id).await.extend()?;
+    Ok(preview::sign(secret, id, expires_in))
+  }
+}
+
+/**
+ * database
+ */
+// get category id by name
+#[instrument(skip(pool, name))]
+async fn category_id(pool: &MySqlPool, name: &str) -> Result<i32, BlogError> {
+  let category = sqlx::query_as::<_, CategoryId>(
+    r#"
+    SELECT id FROM blogapp_category WHERE name = ?
+    "#,
+  )
+  .bind(name)
+  .fetch_one(pool)
+  .await;
+
+  match category {
+    Ok(category) => Ok(category.id),
+    Err(sqlx::Error::RowNotFound) => Err(BlogError::NotFoundCategory),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+// get stored columns of the post (without JOIN)
+#[instrument(skip(pool))]
+async fn stored_post(pool: &MySqlPool, id: i32) -> Result<StoredPost, BlogError> {
+  let post = sqlx::query_as::<_, StoredPost>(
+    r#"
+    SELECT title, category_id, contents, pub_date, open
+    FROM blogapp_post
+    WHERE id = ?
+    "#,
+  )
+  .bind(id)
+  .fetch_one(pool)
+  .await;
+
+  match post {
+    Ok(post) => Ok(post),
+    Err(sqlx::Error::RowNotFound) => Err(BlogError::NotFoundPost),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+// create post
+#[instrument(skip_all)]
+pub async fn create_post(pool: &MySqlPool, input: CreatePostInput) -> Result<Post, BlogError> {
+  let category_id = category_id(pool, &input.category).await?;
+  let pub_date = input.pub_date.unwrap_or_else(Utc::now);
+
+  // タグの保存に失敗した場合は投稿も作らない
+  let mut tx = pool.begin().await.map_err(BlogError::database)?;
+  let result = sqlx::query(
+    r#"
+    INSERT INTO blogapp_post (title, category_id, contents, pub_date, open)
+    VALUES (?, ?, ?, ?, ?)
+    "#,
+  )
+  .bind(input.title)
+  .bind(category_id)
+  .bind(input.contents)
+  .bind(pub_date)
+  .bind(input.open)
+  .execute(&mut tx)
+  .await;
+
+  let id = match result {
+    Ok(result) => result.last_insert_id() as i32,
+    Err(e) => return Err(BlogError::database(e)),
+  };
+  set_post_tags(&mut tx, id, input.tags).await?;
+  tx.commit().await.map_err(BlogError::database)?;
+  get_post(pool, id).await
+}
+
+// update post
+#[instrument(skip(pool, input))]
+pub async fn update_post(pool: &MySqlPool, id: i32, input: UpdatePostInput) -> Result<Post, BlogError> {
+  let stored = stored_post(pool, id).await?;
+  let category_id = match input.category {
+    Some(name) => category_id(pool, &name).await?,
+    None => stored.category_id,
+  };
+  let open = match input.open {
+    Some(open) => open,
+    None => stored.open != 0,
+  };
+
+  // タグの保存に失敗した場合は投稿の更新も戻す
+  let mut tx = pool.begin().await.map_err(BlogError::database)?;
+  let result = sqlx::query(
+    r#"
+    UPDATE blogapp_post
+    SET title = ?, category_id = ?, contents = ?, pub_date = ?, open = ?
+    WHERE id = ?
+    "#,
+  )
+  .bind(input.title.unwrap_or(stored.title))
+  .bind(category_id)
+  .bind(input.contents.or(stored.contents))
+  .bind(input.pub_date.unwrap_or(stored.pub_date))
+  .bind(open)
+  .bind(id)
+  .execute(&mut tx)
+  .await;
+
+  if let Err(e) = result {
+    return Err(BlogError::database(e));
+  }
+  if let Some(tags) = input.tags {
+    set_post_tags(&mut tx, id, tags).await?;
+  }
+  tx.commit().await.map_err(BlogError::database)?;
+  get_post(pool, id).await
+}
+
+// delete post, returns the post as it was before deletion
+#[instrument(skip(pool))]
+pub async fn delete_post(pool: &MySqlPool, id: i32) -> Result<Post, BlogError> {
+  let post = get_post(pool, id).await?;
+
+  let result = sqlx::query(
+    r#"
+    DELETE FROM blogapp_post WHERE id = ?
+    "#,
+  )
+  .bind(id)
+  .execute(pool)
+  .await;
+
+  match result {
+    Ok(_) => Ok(post),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+// publish / unpublish post
+#[instrument(skip(pool))]
+pub async fn set_open(pool: &MySqlPool, id: i32, open: bool) -> Result<Post, BlogError> {
+  // 既に同じ状態だと rows_affected が 0 になるので、存在確認は先にしておく
+  stored_post(pool, id).await?;
+
+  let result = sqlx::query(
+    r#"
+    UPDATE blogapp_post SET open = ? WHERE id = ?
+    "#,
+  )
+  .bind(open)
+  .bind(id)
+  .execute(pool)
+  .await;
+
+  match result {
+    Ok(_) => get_post(pool, id).await,
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn post(open: bool, contents: &str) -> Post {
+    Post {
+      id: 1,
+      title: "title".to_string(),
+      category: Some("rust".to_string()),
+      category_id: 1,
+      contents: Some(contents.to_string()),
+      pub_date: Utc::now(),
+      updated_at: Utc::now(),
+      open: open as i8,
+    }
+  }
+
+  fn contents(event: &PostEvent) -> (&'static str, Option<String>) {
+    match event {
+      PostEvent::Published(post) => ("published", post.contents.clone()),
+      PostEvent::Updated(post) => ("updated", post.contents.clone()),
+      PostEvent::Deleted(post) => ("deleted", post.contents.clone()),
+    }
+  }
+
+  fn events(before: Option<&Post>, after: &Post) -> Vec<(&'static str, Option<String>)> {
+    post_events(before, after).iter().map(contents).collect()
+  }
+
+  #[test]
+  fn created_posts() {
+    assert_eq!(
+      events(None, &post(true, "new")),
+      vec![("published", Some("new".to_string())), ("updated", Some("new".to_string()))],
+    );
+    assert!(events(None, &post(false, "draft")).is_empty());
+  }
+
+  #[test]
+  fn published_posts() {
+    assert_eq!(
+      events(Some(&post(false, "draft")), &post(true, "public")),
+      vec![("published", Some("public".to_string())), ("updated", Some("public".to_string()))],
+    );
+  }
+
+  #[test]
+  fn updated_open_posts() {
+    assert_eq!(
+      events(Some(&post(true, "old")), &post(true, "new")),
+      vec![("updated", Some("new".to_string()))],
+    );
+  }
+
+  #[test]
+  fn updated_drafts_are_not_sent() {
+    assert!(events(Some(&post(false, "draft")), &post(false, "new draft")).is_empty());
+  }
+
+  #[test]
+  fn unpublished_posts_do_not_leak_draft_contents() {
+    assert_eq!(
+      events(Some(&post(true, "public")), &post(false, "new draft")),
+      vec![("deleted", Some("public".to_string()))],
+    );
+  }
+}
@@ -0,0 +1,243 @@
+use chrono::{DateTime, Utc};
+use sqlx::mysql::MySqlPool;
+use std::sync::Arc;
+use tracing::instrument;
+
+use async_graphql::{
+  connection::{query, Connection, CursorType, Edge, EmptyFields},
+  Context,
+  FieldResult,
+  SimpleObject,
+};
+
+use crate::cache::ResolverCache;
+use crate::config::Config;
+use crate::filter::{PostFilter, QueryBuilder};
+use crate::resolvers::{count, BlogError, Post};
+
+pub const MAX_PAGE_SIZE: usize = 100;
+
+// pub_date desc, id desc の並び順で位置を特定するためのカーソル
+// クライアントからは中身を意識させないように base64 で包む
+pub struct PostCursor {
+  pub_date: DateTime<Utc>,
+  id: i32,
+}
+
+impl PostCursor {
+  fn of(post: &Post) -> Self {
+    PostCursor {
+      pub_date: post.pub_date,
+      id: post.id,
+    }
+  }
+}
+
+impl CursorType for PostCursor {
+  type Error = BlogError;
+
+  fn decode_cursor(s: &str) -> Result<Self, Self::Error> {
+    // クライアントが送ってきた値なので、サーバのエラーではなく引数の誤りとして返す
+    let invalid = || BlogError::InvalidArgument("invalid cursor".to_string());
+    let bytes = base64::decode_config(s, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+    let decoded = String::from_utf8(bytes).map_err(|_| invalid())?;
+    let (pub_date, id) = decoded.split_once('|').ok_or_else(invalid)?;
+    Ok(PostCursor {
+      pub_date: DateTime::parse_from_rfc3339(pub_date).map_err(|_| invalid())?.with_timezone(&Utc),
+      id: id.parse().map_err(|_| invalid())?,
+    })
+  }
+
+  fn encode_cursor(&self) -> String {
+    let raw = format!("{}|{}", self.pub_date.to_rfc3339(), self.id);
+    base64::encode_config(raw, base64::URL_SAFE_NO_PAD)
+  }
+}
+
+#[derive(SimpleObject)]
+pub struct PostConnectionFields {
+  total_count: i32,
+}
+
+pub type PostConnection = Connection<PostCursor, Post, PostConnectionFields, EmptyFields>;
+
+/**
+ * resolvers
+ */
+pub async fn posts(
+  ctx: &Context<'_>,
+  after: Option<String>,
+  before: Option<String>,
+  first: Option<i32>,
+  last: Option<i32>,
+  filter: PostFilter,
+) -> FieldResult<PostConnection> {
+  let pool = ctx.data::<MySqlPool>()?.clone();
+  let cache = ctx.data::<Arc<ResolverCache>>()?;
+  let config = &ctx.data::<Arc<Config>>()?.posts;
+  let page_size = config.page_size as usize;
+  let excerpt_length = config.excerpt_length;
+  query(after, before, first, last, |after, before, first, last| async move {
+    // last だけ指定された場合は末尾から遡る
+    let backward = first.is_none() && last.is_some();
+    let limit = match backward {
+      true => last,
+      false => first,
+    }
+    .unwrap_or(page_size)
+    .min(MAX_PAGE_SIZE);
+
+    // getPosts や categories と同じキャッシュを通して、一つのレスポンスの中で件数が食い違わないようにする
+    let total_count = cache.get_or_fetch(format!("count:{:?}", filter), || count(&pool, &filter)).await?;
+    let key = format!(
+      "connection:{:?}:{:?}:{:?}:{}:{}",
+      filter,
+      after.as_ref().map(|cursor: &PostCursor| cursor.encode_cursor()),
+      before.as_ref().map(|cursor: &PostCursor| cursor.encode_cursor()),
+      backward,
+      limit
+    );
+    let mut posts = cache
+      .get_or_fetch(key, || list_posts(&pool, &filter, excerpt_length, &after, &before, backward, limit + 1))
+      .await?;
+
+    // 1件多く取得して、次のページがあるかを判定する
+    let has_more = posts.len() > limit;
+    posts.truncate(limit);
+    if backward {
+      posts.reverse();
+    }
+    let (has_previous_page, has_next_page) = match backward {
+      true => (has_more, before.is_some()),
+      false => (after.is_some(), has_more),
+    };
+
+    let mut connection = Connection::with_additional_fields(
+      has_previous_page,
+      has_next_page,
+      PostConnectionFields { total_count },
+    );
+    connection
+      .edges
+      .extend(posts.into_iter().map(|post| Edge::new(PostCursor::of(&post), post)));
+    Ok::<_, BlogError>(connection)
+  })
+  .await
+}
+
+/**
+ * database
+ */
+// list posts matching the filter before / after the cursors
+#[instrument(skip_all)]
+async fn list_posts(
+  pool: &MySqlPool,
+  filter: &PostFilter,
+  excerpt_length: i32,
+  after: &Option<PostCursor>,
+  before: &Option<PostCursor>,
+  backward: bool,
+  limit: usize,
+) -> Result<Vec<Post>, BlogError> {
+  let mut query = QueryBuilder::new(
+    "
+    SELECT
+      blogapp_post.id,
+      title,
+      blogapp_category.name as category,
+      blogapp_post.category_id,
+      left(contents,",
+  );
+  query.push_bind(excerpt_length).push(
+    ") as contents,
+      pub_date,
+      blogapp_post.updated_at,
+      open
+    FROM
+      blogapp_post
+    INNER JOIN
+      blogapp_category
+    ON
+      blogapp_post.category_id = blogapp_category.id
+    WHERE
+      true
+    ",
+  );
+  filter.apply(&mut query);
+  // 新しい順に並んでいるので、after はそれより古いもの、before はそれより新しいもの
+  if let Some(after) = after {
+    query.push("AND (pub_date, blogapp_post.id) < (").push_bind(after.pub_date).push(",").push_bind(after.id).push(")");
+  }
+  if let Some(before) = before {
+    query.push("AND (pub_date, blogapp_post.id) > (").push_bind(before.pub_date).push(",").push_bind(before.id).push(")");
+  }
+  match backward {
+    true => query.push("ORDER BY pub_date asc, blogapp_post.id asc"),
+    false => query.push("ORDER BY pub_date desc, blogapp_post.id desc"),
+  };
+  query.push("LIMIT").push_bind(limit as i64);
+  let (sql, args) = query.build();
+
+  match sqlx::query_as_with::<_, Post, _>(sql.as_str(), args).fetch_all(pool).await {
+    Ok(posts) => Ok(posts),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn cursor_of(raw: &str) -> String {
+    base64::encode_config(raw, base64::URL_SAFE_NO_PAD)
+  }
+
+  fn is_invalid_argument(result: Result<PostCursor, BlogError>) -> bool {
+    matches!(result, Err(BlogError::InvalidArgument(_)))
+  }
+
+  #[test]
+  fn cursor_round_trip() {
+    let encoded = cursor_of("2022-04-01T12:34:56+00:00|42");
+    let cursor = PostCursor::decode_cursor(&encoded).unwrap();
+    assert_eq!(cursor.encode_cursor(), encoded);
+  }
+
+  #[test]
+  fn cursor_normalizes_timezone_to_utc() {
+    let cursor = PostCursor::decode_cursor(&cursor_of("2022-04-01T21:34:56+09:00|42")).unwrap();
+    assert_eq!(cursor.encode_cursor(), cursor_of("2022-04-01T12:34:56+00:00|42"));
+  }
+
+  #[test]
+  fn cursor_rejects_invalid_base64() {
+    assert!(is_invalid_argument(PostCursor::decode_cursor("not base64!")));
+  }
+
+  #[test]
+  fn cursor_rejects_invalid_utf8() {
+    let encoded = base64::encode_config([0xff, 0xfe, 0xfd], base64::URL_SAFE_NO_PAD);
+    assert!(is_invalid_argument(PostCursor::decode_cursor(&encoded)));
+  }
+
+  #[test]
+  fn cursor_rejects_missing_separator() {
+    assert!(is_invalid_argument(PostCursor::decode_cursor(&cursor_of("2022-04-01T12:34:56+00:00"))));
+  }
+
+  #[test]
+  fn cursor_rejects_invalid_date() {
+    assert!(is_invalid_argument(PostCursor::decode_cursor(&cursor_of("yesterday|42"))));
+  }
+
+  #[test]
+  fn cursor_rejects_invalid_id() {
+    assert!(is_invalid_argument(PostCursor::decode_cursor(&cursor_of("2022-04-01T12:34:56+00:00|abc"))));
+    assert!(is_invalid_argument(PostCursor::decode_cursor(&cursor_of("2022-04-01T12:34:56+00:00|"))));
+  }
+
+  #[test]
+  fn cursor_rejects_empty_string() {
+    assert!(is_invalid_argument(PostCursor::decode_cursor("")));
+  }
+}
@@ -0,0 +1,213 @@
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+
+use async_graphql::{
+  extensions::{
+    Extension,
+    ExtensionContext,
+    ExtensionFactory,
+    NextPrepareRequest,
+  },
+  ErrorExtensionValues,
+  Request,
+  ServerError,
+  ServerResult,
+  Value,
+};
+
+// Apollo のクライアントはこのメッセージを見て、クエリ本文を付けて送り直す
+const NOT_FOUND: &str = "PersistedQueryNotFound";
+
+type Store = Arc<Mutex<LruCache<String, Arc<String>>>>;
+
+fn persisted_query_error(message: &str, code: &str) -> ServerError {
+  let mut extensions = ErrorExtensionValues::default();
+  extensions.set("code", code);
+  let mut error = ServerError::new(message, None);
+  error.extensions = Some(extensions);
+  error
+}
+
+fn sha256_hex(query: &str) -> String {
+  Sha256::digest(query.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// extensions.persistedQuery の sha256Hash を取り出す
+fn persisted_query_hash(request: &Request) -> Result<Option<String>, ServerError> {
+  let persisted_query = match request.extensions.get("persistedQuery") {
+    Some(Value::Object(persisted_query)) => persisted_query,
+    Some(_) => return Err(persisted_query_error("persistedQuery must be an object", "BAD_REQUEST")),
+    None => return Ok(None),
+  };
+  match persisted_query.get("version") {
+    Some(Value::Number(version)) if version.as_i64() == Some(1) => (),
+    _ => return Err(persisted_query_error("PersistedQueryNotSupported", "PERSISTED_QUERY_NOT_SUPPORTED")),
+  }
+  match persisted_query.get("sha256Hash") {
+    Some(Value::String(hash)) => Ok(Some(hash.to_lowercase())),
+    _ => Err(persisted_query_error("sha256Hash is required", "BAD_REQUEST")),
+  }
+}
+
+// Apollo 互換の Automatic Persisted Queries
+// ハッシュだけのリクエストは保存済みのクエリで実行し、なければ PersistedQueryNotFound を返す
+// クエリ本文付きのリクエストはハッシュが一致した場合だけ保存する (別のクエリで上書きされないように)
+pub struct PersistedQueries {
+  store: Store,
+}
+
+impl PersistedQueries {
+  pub fn new(capacity: usize) -> Self {
+    PersistedQueries {
+      store: Arc::new(Mutex::new(LruCache::new(capacity))),
+    }
+  }
+}
+
+impl ExtensionFactory for PersistedQueries {
+  fn create(&self) -> Arc<dyn Extension> {
+    Arc::new(PersistedQueriesExtension {
+      store: self.store.clone(),
+    })
+  }
+}
+
+struct PersistedQueriesExtension {
+  store: Store,
+}
+
+#[async_trait::async_trait]
+impl Extension for PersistedQueriesExtension {
+  async fn prepare_request(
+    &self,
+    ctx: &ExtensionContext<'_>,
+    mut request: Request,
+    next: NextPrepareRequest<'_>,
+  ) -> ServerResult<Request> {
+    let hash = match persisted_query_hash(&request)? {
+      Some(hash) => hash,
+      None => return next.run(ctx, request).await,
+    };
+
+    if request.query.is_empty() {
+      let query = self.store.lock().unwrap().get(&hash).cloned();
+      match query {
+        Some(query) => request.query = query.to_string(),
+        None => return Err(persisted_query_error(NOT_FOUND, "PERSISTED_QUERY_NOT_FOUND")),
+      }
+    } else {
+      if sha256_hex(&request.query) != hash {
+        return Err(persisted_query_error("provided sha does not match query", "BAD_REQUEST"));
+      }
+      self.store.lock().unwrap().put(hash, Arc::new(request.query.clone()));
+    }
+    next.run(ctx, request).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use async_graphql::{EmptyMutation, EmptySubscription, Name, Object, Response, Schema};
+
+  struct Query;
+
+  #[Object]
+  impl Query {
+    async fn value(&self) -> i32 {
+      1
+    }
+
+    async fn other(&self) -> i32 {
+      2
+    }
+  }
+
+  fn schema(capacity: usize) -> Schema<Query, EmptyMutation, EmptySubscription> {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+      .extension(PersistedQueries::new(capacity))
+      .finish()
+  }
+
+  fn request(query: &str, hash: &str) -> Request {
+    let mut persisted_query = async_graphql::indexmap::IndexMap::new();
+    persisted_query.insert(Name::new("version"), Value::from(1));
+    persisted_query.insert(Name::new("sha256Hash"), Value::from(hash));
+    let mut request = Request::new(query);
+    request.extensions.insert("persistedQuery".to_string(), Value::Object(persisted_query));
+    request
+  }
+
+  fn code(response: &Response) -> String {
+    let error = serde_json::to_value(&response.errors[0]).unwrap();
+    error["extensions"]["code"].as_str().unwrap_or_default().to_string()
+  }
+
+  #[tokio::test]
+  async fn unknown_hash_is_not_found() {
+    let response = schema(2).execute(request("", &sha256_hex("{ value }"))).await;
+    assert_eq!(response.errors[0].message, NOT_FOUND);
+    assert_eq!(code(&response), "PERSISTED_QUERY_NOT_FOUND");
+  }
+
+  #[tokio::test]
+  async fn registered_query_is_executed_by_hash() {
+    let schema = schema(2);
+    let hash = sha256_hex("{ value }");
+    let response = schema.execute(request("{ value }", &hash)).await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+    let response = schema.execute(request("", &hash)).await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    assert_eq!(response.data.to_string(), "{value: 1}");
+
+    // ハッシュは大文字でも同じクエリを指す
+    let response = schema.execute(request("", &hash.to_uppercase())).await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+  }
+
+  #[tokio::test]
+  async fn mismatched_hash_is_rejected_and_not_stored() {
+    let schema = schema(2);
+    let hash = sha256_hex("{ value }");
+    let response = schema.execute(request("{ other }", &hash)).await;
+    assert_eq!(response.errors[0].message, "provided sha does not match query");
+    assert_eq!(code(&response), "BAD_REQUEST");
+
+    let response = schema.execute(request("", &hash)).await;
+    assert_eq!(code(&response), "PERSISTED_QUERY_NOT_FOUND");
+  }
+
+  #[tokio::test]
+  async fn least_recently_used_query_is_evicted() {
+    let schema = schema(2);
+    let queries = ["{ value }", "{ other }", "{ value other }"];
+    let hashes: Vec<String> = queries.iter().map(|query| sha256_hex(query)).collect();
+    schema.execute(request(queries[0], &hashes[0])).await;
+    schema.execute(request(queries[1], &hashes[1])).await;
+    // 0 を使ったので、次に追い出されるのは 1
+    assert!(schema.execute(request("", &hashes[0])).await.errors.is_empty());
+    schema.execute(request(queries[2], &hashes[2])).await;
+
+    assert_eq!(code(&schema.execute(request("", &hashes[1])).await), "PERSISTED_QUERY_NOT_FOUND");
+    assert!(schema.execute(request("", &hashes[0])).await.errors.is_empty());
+    assert!(schema.execute(request("", &hashes[2])).await.errors.is_empty());
+  }
+
+  #[tokio::test]
+  async fn requests_without_persisted_query_pass_through() {
+    let response = schema(2).execute(Request::new("{ value }")).await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+  }
+
+  #[tokio::test]
+  async fn unsupported_version_is_rejected() {
+    let mut request = request("", &sha256_hex("{ value }"));
+    if let Some(Value::Object(persisted_query)) = request.extensions.get_mut("persistedQuery") {
+      persisted_query.insert(Name::new("version"), Value::from(2));
+    }
+    let response = schema(2).execute(request).await;
+    assert_eq!(code(&response), "PERSISTED_QUERY_NOT_SUPPORTED");
+  }
+}
@@ -0,0 +1,219 @@
+use chrono::{DateTime, Duration, SubsecRound, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use async_graphql::SimpleObject;
+
+use crate::config::PreviewConfig;
+use crate::resolvers::BlogError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// 下書きを共有するためのトークンの署名鍵
+// 起動時に設定から読み込む。未設定の場合はプレビューが使えない
+pub struct PreviewSecret(Vec<u8>);
+
+#[derive(SimpleObject)]
+pub struct PreviewToken {
+  token: String,
+  expires_at: DateTime<Utc>,
+}
+
+pub fn preview_secret(config: &PreviewConfig) -> Option<PreviewSecret> {
+  config.secret.as_ref().map(|secret| PreviewSecret(secret.clone().into_bytes()))
+}
+
+fn mac(secret: &PreviewSecret, payload: &str) -> HmacSha256 {
+  // HMAC はどんな長さの鍵も受け付けるので失敗しない
+  let mut mac = HmacSha256::new_from_slice(&secret.0).expect("HMAC accepts keys of any size");
+  mac.update(payload.as_bytes());
+  mac
+}
+
+// プレビュー用トークンは既定で 1 週間有効
+const DEFAULT_EXPIRES_IN: i64 = 60 * 60 * 24 * 7;
+
+// expiresIn (秒) を確かめて有効期間にする。未指定なら既定値(最大値を超える場合は最大値)
+pub fn expires_in(config: &PreviewConfig, expires_in: Option<i64>) -> Result<Duration, BlogError> {
+  let expires_in = expires_in.unwrap_or_else(|| DEFAULT_EXPIRES_IN.min(config.max_expires_in));
+  if expires_in <= 0 {
+    return Err(BlogError::InvalidArgument("expiresIn must be positive".to_string()));
+  }
+  if expires_in > config.max_expires_in {
+    return Err(BlogError::InvalidArgument(format!("expiresIn must not exceed {}", config.max_expires_in)));
+  }
+  Ok(Duration::seconds(expires_in))
+}
+
+// トークンは `base64(post_id.expires).base64(hmac)` の形
+pub fn sign(secret: &PreviewSecret, post_id: i32, expires_in: Duration) -> PreviewToken {
+  let expires_at = Utc::now() + expires_in;
+  let payload = format!("{}.{}", post_id, expires_at.timestamp());
+  let signature = mac(secret, &payload).finalize().into_bytes();
+  PreviewToken {
+    token: format!(
+      "{}.{}",
+      base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+      base64::encode_config(signature, base64::URL_SAFE_NO_PAD),
+    ),
+    // トークンには秒までしか入らない
+    expires_at: expires_at.trunc_subsecs(0),
+  }
+}
+
+// 署名と有効期限を確かめて、プレビューを許可する post_id を返す
+pub fn verify(secret: &PreviewSecret, token: &str) -> Result<i32, BlogError> {
+  let (payload, signature) = token.split_once('.').ok_or(BlogError::InvalidPreviewToken)?;
+  let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).map_err(|_| BlogError::InvalidPreviewToken)?;
+  let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).map_err(|_| BlogError::InvalidPreviewToken)?;
+  let payload = String::from_utf8(payload).map_err(|_| BlogError::InvalidPreviewToken)?;
+
+  // verify_slice は定数時間で比較する
+  mac(secret, &payload)
+    .verify_slice(&signature)
+    .map_err(|_| BlogError::InvalidPreviewToken)?;
+
+  let (post_id, expires) = payload.split_once('.').ok_or(BlogError::InvalidPreviewToken)?;
+  let expires: i64 = expires.parse().map_err(|_| BlogError::InvalidPreviewToken)?;
+  if expires < Utc::now().timestamp() {
+    return Err(BlogError::InvalidPreviewToken);
+  }
+  post_id.parse().map_err(|_| BlogError::InvalidPreviewToken)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn secret() -> PreviewSecret {
+    PreviewSecret(b"preview-secret".to_vec())
+  }
+
+  fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+  }
+
+  // 任意の payload に正しい署名を付ける
+  fn signed(payload: &str) -> String {
+    let signature = mac(&secret(), payload).finalize().into_bytes();
+    format!("{}.{}", encode(payload.as_bytes()), encode(&signature))
+  }
+
+  fn is_invalid(result: Result<i32, BlogError>) -> bool {
+    matches!(result, Err(BlogError::InvalidPreviewToken))
+  }
+
+  fn config(max_expires_in: i64) -> PreviewConfig {
+    PreviewConfig {
+      secret: None,
+      max_expires_in,
+    }
+  }
+
+  fn is_invalid_argument(result: Result<Duration, BlogError>) -> bool {
+    matches!(result, Err(BlogError::InvalidArgument(_)))
+  }
+
+  #[test]
+  fn expires_in_defaults_to_a_week() {
+    assert_eq!(expires_in(&config(60 * 60 * 24 * 30), None).unwrap(), Duration::days(7));
+    // 最大値の方が短い場合は最大値
+    assert_eq!(expires_in(&config(60), None).unwrap(), Duration::seconds(60));
+  }
+
+  #[test]
+  fn expires_in_up_to_the_maximum() {
+    assert_eq!(expires_in(&config(3600), Some(3600)).unwrap(), Duration::hours(1));
+    assert!(is_invalid_argument(expires_in(&config(3600), Some(3601))));
+  }
+
+  #[test]
+  fn expires_in_rejects_out_of_range_values() {
+    assert!(is_invalid_argument(expires_in(&config(3600), Some(0))));
+    assert!(is_invalid_argument(expires_in(&config(3600), Some(-1))));
+    // Duration や日時の計算で panic しない
+    assert!(is_invalid_argument(expires_in(&config(3600), Some(i64::MAX))));
+    assert!(is_invalid_argument(expires_in(&config(3600), Some(i64::MIN))));
+  }
+
+  #[test]
+  fn round_trip() {
+    let token = sign(&secret(), 42, Duration::hours(1));
+    assert_eq!(verify(&secret(), &token.token).unwrap(), 42);
+    assert!(token.expires_at > Utc::now());
+  }
+
+  #[test]
+  fn expired_token() {
+    let token = sign(&secret(), 42, Duration::seconds(-10));
+    assert!(is_invalid(verify(&secret(), &token.token)));
+  }
+
+  #[test]
+  fn tampered_signature() {
+    let token = sign(&secret(), 42, Duration::hours(1)).token;
+    let (payload, signature) = token.split_once('.').unwrap();
+    let mut signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap();
+    signature[0] ^= 1;
+    assert!(is_invalid(verify(&secret(), &format!("{}.{}", payload, encode(&signature)))));
+  }
+
+  #[test]
+  fn truncated_signature() {
+    let token = sign(&secret(), 42, Duration::hours(1)).token;
+    let (payload, signature) = token.split_once('.').unwrap();
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap();
+    assert!(is_invalid(verify(&secret(), &format!("{}.{}", payload, encode(&signature[..16])))));
+    assert!(is_invalid(verify(&secret(), &format!("{}.", payload))));
+  }
+
+  #[test]
+  fn signature_of_another_post() {
+    // id だけ書き換えて、元の署名をそのまま使う
+    let token = sign(&secret(), 42, Duration::hours(1)).token;
+    let (payload, signature) = token.split_once('.').unwrap();
+    let payload = String::from_utf8(base64::decode_config(payload, base64::URL_SAFE_NO_PAD).unwrap()).unwrap();
+    let forged = payload.replacen("42.", "43.", 1);
+    assert!(is_invalid(verify(&secret(), &format!("{}.{}", encode(forged.as_bytes()), signature))));
+  }
+
+  #[test]
+  fn extended_expiry() {
+    let token = sign(&secret(), 42, Duration::seconds(-10)).token;
+    let (_, signature) = token.split_once('.').unwrap();
+    let forged = format!("42.{}", (Utc::now() + Duration::hours(1)).timestamp());
+    assert!(is_invalid(verify(&secret(), &format!("{}.{}", encode(forged.as_bytes()), signature))));
+  }
+
+  #[test]
+  fn signed_with_another_secret() {
+    let token = sign(&PreviewSecret(b"another-secret".to_vec()), 42, Duration::hours(1));
+    assert!(is_invalid(verify(&secret(), &token.token)));
+  }
+
+  #[test]
+  fn malformed_base64() {
+    assert!(is_invalid(verify(&secret(), "!!!.???")));
+    let token = sign(&secret(), 42, Duration::hours(1)).token;
+    let (payload, _) = token.split_once('.').unwrap();
+    assert!(is_invalid(verify(&secret(), &format!("{}.not base64", payload))));
+  }
+
+  #[test]
+  fn malformed_token() {
+    assert!(is_invalid(verify(&secret(), "")));
+    assert!(is_invalid(verify(&secret(), "no-separator")));
+  }
+
+  #[test]
+  fn malformed_payload_with_valid_signature() {
+    let expires = (Utc::now() + Duration::hours(1)).timestamp();
+    assert!(is_invalid(verify(&secret(), &signed(&format!("abc.{}", expires)))));
+    assert!(is_invalid(verify(&secret(), &signed("42.tomorrow"))));
+    assert!(is_invalid(verify(&secret(), &signed("42"))));
+
+    let signature = mac(&secret(), "").finalize().into_bytes();
+    let not_utf8 = format!("{}.{}", encode(&[0xff, 0xfe]), encode(&signature));
+    assert!(is_invalid(verify(&secret(), &not_utf8)));
+  }
+}
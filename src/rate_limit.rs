@@ -0,0 +1,457 @@
+use lru::LruCache;
+use std::{
+  collections::HashSet,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr},
+  str::FromStr,
+  sync::{Arc, Mutex},
+  time::Instant,
+};
+
+use async_graphql::{
+  extensions::{
+    Extension,
+    ExtensionContext,
+    ExtensionFactory,
+    NextParseQuery,
+    NextPrepareRequest,
+  },
+  parser::types::{ExecutableDocument, OperationType},
+  ErrorExtensionValues,
+  Request,
+  ServerError,
+  ServerResult,
+  Variables,
+};
+use axum::{
+  http::{header, HeaderMap, HeaderValue, StatusCode},
+  response::Response,
+};
+
+use crate::config::RateLimitConfig;
+use crate::http_get::operation_type;
+use crate::metrics;
+
+pub const API_KEY: &str = "x-api-key";
+pub const RATE_LIMIT_LIMIT: &str = "ratelimit-limit";
+pub const RATE_LIMIT_REMAINING: &str = "ratelimit-remaining";
+pub const RATE_LIMIT_RESET: &str = "ratelimit-reset";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+// X-Forwarded-For を信用するプロキシのアドレス (192.168.0.1 や 10.0.0.0/8 など)
+pub struct TrustedProxy {
+  network: IpAddr,
+  prefix: u32,
+}
+
+impl FromStr for TrustedProxy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (address, prefix) = match s.split_once('/') {
+      Some((address, prefix)) => (address, Some(prefix)),
+      None => (s, None),
+    };
+    let network: IpAddr = address.trim().parse().map_err(|_| format!("invalid address: {}", s))?;
+    let bits = if network.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+      Some(prefix) => match prefix.trim().parse::<u32>() {
+        Ok(prefix) if prefix <= bits => prefix,
+        _ => return Err(format!("invalid prefix length: {}", s)),
+      },
+      None => bits,
+    };
+    Ok(TrustedProxy { network, prefix })
+  }
+}
+
+impl TrustedProxy {
+  fn contains(&self, ip: IpAddr) -> bool {
+    match (self.network, ip) {
+      (IpAddr::V4(network), IpAddr::V4(ip)) => {
+        same_prefix(u32::from(network).into(), u32::from(ip).into(), 32, self.prefix)
+      },
+      (IpAddr::V6(network), IpAddr::V6(ip)) => same_prefix(u128::from(network), u128::from(ip), 128, self.prefix),
+      _ => false,
+    }
+  }
+}
+
+// IPv6 は一つの回線に /64 がまとめて割り当てられ、アドレスを変えて制限を逃れられるので /64 単位で数える
+// IPv4 射影アドレス (::ffff:192.0.2.1) は IPv4 として扱う
+fn client_network(ip: IpAddr) -> String {
+  let ip = match ip {
+    IpAddr::V4(ip) => return ip.to_string(),
+    IpAddr::V6(ip) => ip,
+  };
+  match ip.octets() {
+    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Ipv4Addr::new(a, b, c, d).to_string(),
+    _ => format!("{}/64", Ipv6Addr::from(u128::from(ip) & (u128::MAX << 64))),
+  }
+}
+
+fn same_prefix(network: u128, ip: u128, bits: u32, prefix: u32) -> bool {
+  // /0 は全てのアドレスを含む (128 ビットのシフトは溢れる)
+  if prefix == 0 {
+    return true;
+  }
+  (network >> (bits - prefix)) == (ip >> (bits - prefix))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Budget {
+  Query,
+  Mutation,
+}
+
+impl Budget {
+  fn name(&self) -> &'static str {
+    match self {
+      Budget::Query => "query",
+      Budget::Mutation => "mutation",
+    }
+  }
+}
+
+struct Rate {
+  per_second: f64,
+  burst: f64,
+}
+
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+// レスポンスに載せる残りの回数。秒は切り上げる
+#[derive(Clone, Copy)]
+pub struct Decision {
+  limit: u32,
+  remaining: u32,
+  // バケットが満杯に戻るまでの秒数
+  reset: u64,
+  // 制限を超えた場合、次のトークンが補充されるまでの秒数
+  retry_after: Option<u64>,
+}
+
+// クライアント (IP アドレスか API キー) と operation の種類ごとのトークンバケット
+pub struct RateLimiter {
+  query: Rate,
+  mutation: Rate,
+  trusted_proxies: Vec<TrustedProxy>,
+  api_keys: HashSet<String>,
+  buckets: Mutex<LruCache<(String, Budget), Bucket>>,
+}
+
+impl RateLimiter {
+  pub fn new(config: &RateLimitConfig) -> Self {
+    RateLimiter {
+      query: Rate {
+        per_second: config.queries_per_minute as f64 / 60.0,
+        burst: config.query_burst as f64,
+      },
+      mutation: Rate {
+        per_second: config.mutations_per_minute as f64 / 60.0,
+        burst: config.mutation_burst as f64,
+      },
+      // 有効な場合は Config::validate で確認済み
+      trusted_proxies: config.trusted_proxies.iter().filter_map(|proxy| proxy.parse().ok()).collect(),
+      api_keys: config.api_keys.iter().cloned().collect(),
+      buckets: Mutex::new(LruCache::new(config.max_clients)),
+    }
+  }
+
+  fn is_trusted(&self, ip: IpAddr) -> bool {
+    self.trusted_proxies.iter().any(|proxy| proxy.contains(ip))
+  }
+
+  // 接続元が信用するプロキシの場合だけ X-Forwarded-For を見る
+  // 右から辿って、信用するプロキシでない最初のアドレスをクライアントとする (左側は偽装できる)
+  fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    if !self.is_trusted(peer) {
+      return peer;
+    }
+    let forwarded: Vec<IpAddr> = headers
+      .get_all(X_FORWARDED_FOR)
+      .iter()
+      .filter_map(|v| v.to_str().ok())
+      .flat_map(|v| v.split(','))
+      .filter_map(|ip| ip.trim().parse().ok())
+      .collect();
+    let mut client = peer;
+    for ip in forwarded.into_iter().rev() {
+      client = ip;
+      if !self.is_trusted(ip) {
+        break;
+      }
+    }
+    client
+  }
+
+  // 登録されていない API キーは無視する (キーを変えて制限を逃れられないように)
+  fn client_key(&self, peer: IpAddr, headers: &HeaderMap) -> String {
+    match headers.get(API_KEY).and_then(|v| v.to_str().ok()) {
+      Some(key) if self.api_keys.contains(key) => format!("key:{}", key),
+      _ => format!("ip:{}", client_network(self.client_ip(peer, headers))),
+    }
+  }
+
+  // リクエスト (WebSocket の場合は接続) ごとの状態を作る。RateLimit 拡張がリクエストごとにトークンを取る
+  pub fn prepare(&self, peer: IpAddr, headers: &HeaderMap, operation_name: Option<String>) -> Arc<RateLimitRequest> {
+    Arc::new(RateLimitRequest {
+      client: self.client_key(peer, headers),
+      operation_name,
+      decision: Mutex::new(None),
+    })
+  }
+
+  fn take(&self, client: &str, budget: Budget) -> Decision {
+    let rate = match budget {
+      Budget::Query => &self.query,
+      Budget::Mutation => &self.mutation,
+    };
+    let now = Instant::now();
+    let key = (client.to_string(), budget);
+
+    let mut buckets = self.buckets.lock().unwrap();
+    if !buckets.contains(&key) {
+      buckets.put(key.clone(), Bucket { tokens: rate.burst, updated: now });
+    }
+    let bucket = buckets.get_mut(&key).unwrap();
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * rate.per_second).min(rate.burst);
+    bucket.updated = now;
+
+    let retry_after = match bucket.tokens >= 1.0 {
+      true => {
+        bucket.tokens -= 1.0;
+        None
+      },
+      false => Some((((1.0 - bucket.tokens) / rate.per_second).ceil() as u64).max(1)),
+    };
+    Decision {
+      limit: rate.burst as u32,
+      remaining: bucket.tokens.floor() as u32,
+      reset: ((rate.burst - bucket.tokens) / rate.per_second).ceil() as u64,
+      retry_after,
+    }
+  }
+}
+
+// GraphQL の Request に載せて、拡張で取ったトークンの結果をハンドラに返す
+pub struct RateLimitRequest {
+  client: String,
+  operation_name: Option<String>,
+  decision: Mutex<Option<Decision>>,
+}
+
+impl RateLimitRequest {
+  // RateLimit-* ヘッダを付け、制限を超えていれば 429 と Retry-After を返す
+  // 拡張が無効な場合はトークンを取らないので、ヘッダも付けない
+  pub fn apply(&self, response: &mut Response) {
+    let decision = match *self.decision.lock().unwrap() {
+      Some(decision) => decision,
+      None => return,
+    };
+    let headers = response.headers_mut();
+    headers.insert(RATE_LIMIT_LIMIT, HeaderValue::from(decision.limit));
+    headers.insert(RATE_LIMIT_REMAINING, HeaderValue::from(decision.remaining));
+    headers.insert(RATE_LIMIT_RESET, HeaderValue::from(decision.reset));
+    if let Some(retry_after) = decision.retry_after {
+      headers.insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+      *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    }
+  }
+}
+
+fn rate_limited(retry_after: u64) -> ServerError {
+  let mut extensions = ErrorExtensionValues::default();
+  extensions.set("code", "RATE_LIMITED");
+  extensions.set("retryAfter", retry_after);
+  let mut error = ServerError::new(format!("rate limit exceeded, retry after {} seconds", retry_after), None);
+  error.extensions = Some(extensions);
+  error
+}
+
+fn missing_context() -> ServerError {
+  ServerError::new("rate limit context is missing", None)
+}
+
+// 他の拡張より外側に置き、APQ やパース、QueryLimits / QueryOnly で弾かれたリクエストも数える
+// 実行できる document だけを operation の種類で分け、弾かれたものは query のバケットから取る
+pub struct RateLimit {
+  limiter: Arc<RateLimiter>,
+}
+
+impl RateLimit {
+  pub fn new(limiter: Arc<RateLimiter>) -> Self {
+    RateLimit { limiter }
+  }
+}
+
+impl ExtensionFactory for RateLimit {
+  fn create(&self) -> Arc<dyn Extension> {
+    Arc::new(RateLimitExtension {
+      limiter: self.limiter.clone(),
+    })
+  }
+}
+
+struct RateLimitExtension {
+  limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitExtension {
+  fn take(&self, request: &RateLimitRequest, budget: Budget) -> ServerResult<()> {
+    let decision = self.limiter.take(&request.client, budget);
+    *request.decision.lock().unwrap() = Some(decision);
+    match decision.retry_after {
+      Some(retry_after) => {
+        metrics::record_rate_limited(budget.name());
+        Err(rate_limited(retry_after))
+      },
+      None => Ok(()),
+    }
+  }
+}
+
+#[async_trait::async_trait]
+impl Extension for RateLimitExtension {
+  // APQ のハッシュが見つからない場合は parse_query まで進まないので、ここで数える
+  async fn prepare_request(
+    &self,
+    ctx: &ExtensionContext<'_>,
+    request: Request,
+    next: NextPrepareRequest<'_>,
+  ) -> ServerResult<Request> {
+    // ハンドラか /ws の接続で載せる。載っていないリクエストは数えられないので実行しない
+    let limit = ctx.data_opt::<Arc<RateLimitRequest>>().ok_or_else(missing_context)?;
+    match next.run(ctx, request).await {
+      Ok(request) => Ok(request),
+      Err(e) => {
+        self.take(limit, Budget::Query)?;
+        Err(e)
+      },
+    }
+  }
+
+  async fn parse_query(
+    &self,
+    ctx: &ExtensionContext<'_>,
+    query: &str,
+    variables: &Variables,
+    next: NextParseQuery<'_>,
+  ) -> ServerResult<ExecutableDocument> {
+    let limit = ctx.data_opt::<Arc<RateLimitRequest>>().ok_or_else(missing_context)?;
+    let document = match next.run(ctx, query, variables).await {
+      Ok(document) => document,
+      Err(e) => {
+        self.take(limit, Budget::Query)?;
+        return Err(e);
+      },
+    };
+
+    let budget = match operation_type(&document, limit.operation_name.as_deref()) {
+      Some(OperationType::Mutation) => Budget::Mutation,
+      _ => Budget::Query,
+    };
+    self.take(limit, budget)?;
+    Ok(document)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn limiter(trusted_proxies: &[&str], api_keys: &[&str]) -> RateLimiter {
+    let config = RateLimitConfig {
+      trusted_proxies: trusted_proxies.iter().map(|proxy| proxy.to_string()).collect(),
+      api_keys: api_keys.iter().map(|key| key.to_string()).collect(),
+      ..RateLimitConfig::default()
+    };
+    RateLimiter::new(&config)
+  }
+
+  fn forwarded(value: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(X_FORWARDED_FOR, HeaderValue::from_str(value).unwrap());
+    headers
+  }
+
+  fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn ipv4_clients_by_address() {
+    let limiter = limiter(&[], &[]);
+    assert_eq!(limiter.client_key(ip("192.0.2.1"), &HeaderMap::new()), "ip:192.0.2.1");
+  }
+
+  #[test]
+  fn ipv6_clients_by_64() {
+    let limiter = limiter(&[], &[]);
+    let a = limiter.client_key(ip("2001:db8:1:2:aaaa::1"), &HeaderMap::new());
+    let b = limiter.client_key(ip("2001:db8:1:2:bbbb::2"), &HeaderMap::new());
+    let c = limiter.client_key(ip("2001:db8:1:3::1"), &HeaderMap::new());
+    assert_eq!(a, "ip:2001:db8:1:2::/64");
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+  }
+
+  #[test]
+  fn ipv4_mapped_clients_as_ipv4() {
+    let limiter = limiter(&[], &[]);
+    assert_eq!(limiter.client_key(ip("::ffff:192.0.2.1"), &HeaderMap::new()), "ip:192.0.2.1");
+  }
+
+  #[test]
+  fn forwarded_for_only_from_trusted_proxies() {
+    let limiter = limiter(&["10.0.0.0/8"], &[]);
+    let headers = forwarded("198.51.100.1, 10.0.0.2");
+    assert_eq!(limiter.client_key(ip("10.0.0.1"), &headers), "ip:198.51.100.1");
+    assert_eq!(limiter.client_key(ip("192.0.2.1"), &headers), "ip:192.0.2.1");
+  }
+
+  #[test]
+  fn forwarded_for_ignores_spoofed_left_entries() {
+    let limiter = limiter(&["10.0.0.0/8"], &[]);
+    let headers = forwarded("203.0.113.9, 198.51.100.1");
+    assert_eq!(limiter.client_key(ip("10.0.0.1"), &headers), "ip:198.51.100.1");
+  }
+
+  #[test]
+  fn registered_api_keys_only() {
+    let limiter = limiter(&[], &["secret"]);
+    let mut headers = HeaderMap::new();
+    headers.insert(API_KEY, HeaderValue::from_static("secret"));
+    assert_eq!(limiter.client_key(ip("192.0.2.1"), &headers), "key:secret");
+    headers.insert(API_KEY, HeaderValue::from_static("unknown"));
+    assert_eq!(limiter.client_key(ip("192.0.2.1"), &headers), "ip:192.0.2.1");
+  }
+
+  #[test]
+  fn buckets_per_client_and_budget() {
+    let config = RateLimitConfig {
+      query_burst: 2,
+      mutation_burst: 1,
+      ..RateLimitConfig::default()
+    };
+    let limiter = RateLimiter::new(&config);
+    assert!(limiter.take("a", Budget::Query).retry_after.is_none());
+    assert!(limiter.take("a", Budget::Query).retry_after.is_none());
+    assert!(limiter.take("a", Budget::Query).retry_after.is_some());
+    assert!(limiter.take("a", Budget::Mutation).retry_after.is_none());
+    assert!(limiter.take("a", Budget::Mutation).retry_after.is_some());
+    assert!(limiter.take("b", Budget::Query).retry_after.is_none());
+  }
+
+  #[test]
+  fn trusted_proxy_ranges() {
+    let proxy: TrustedProxy = "10.0.0.0/8".parse().unwrap();
+    assert!(proxy.contains(ip("10.1.2.3")));
+    assert!(!proxy.contains(ip("11.0.0.1")));
+    assert!("10.0.0.0/33".parse::<TrustedProxy>().is_err());
+    assert!("localhost".parse::<TrustedProxy>().is_err());
+  }
+}
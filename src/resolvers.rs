@@ -1,9 +1,31 @@
 use chrono::{DateTime, Utc};
-use sqlx::{mysql::MySqlPool};
-use std::env;
+use sqlx::{
+  mysql::{MySqlConnectOptions, MySqlPool, MySqlPoolOptions},
+  ConnectOptions,
+};
+use std::{str::FromStr, sync::Arc, time::Duration};
+use tracing::instrument;
+
+use crate::auth::{is_admin, Viewer};
+use crate::cache::ResolverCache;
+use crate::categories::{get_categories, get_category, Category};
+use crate::config::{Config, DatabaseConfig, PostsConfig};
+use crate::filter::{PostFilter, QueryBuilder, Visibility};
+use crate::health::{self, check_database, DatabaseHealth, Readiness};
+use crate::http_cache::NoStore;
+use crate::pagination::{self, PostConnection};
+use crate::preview::{self, PreviewSecret};
+use crate::search::{self, SearchResults};
+use crate::limits;
+use crate::loaders::{CategoryLoader, TagsLoader};
+use crate::markdown::MarkdownRenderer;
+use crate::metrics;
+use crate::tags::{get_posts_by_tags, get_tags, Tag, TagMatch};
 
 use async_graphql::{
+  dataloader::DataLoader,
   Object,
+  ComplexObject,
   Context,
   SimpleObject,
   ErrorExtensions, 
@@ -13,10 +35,12 @@ use async_graphql::{
 };
 
 #[derive(SimpleObject)]
-#[derive(sqlx::FromRow)]
 struct Ping {
   status: String,
   code: i32,
+  database: DatabaseHealth,
+  version: String,
+  uptime_seconds: u64,
 }
 
 #[derive(sqlx::FromRow)]
@@ -24,31 +48,63 @@ struct Count {
   count: i64,
 }
 
-#[derive(SimpleObject)]
+#[derive(Clone, SimpleObject)]
 #[derive(sqlx::FromRow)]
+#[graphql(complex)]
 pub struct Post {
-  id: i32,
-  title: String,
-  category: Option<String>,
-  contents: Option<String>,
-  pub_date: DateTime<Utc>,
-  open: i8,
+  pub(crate) id: i32,
+  pub(crate) title: String,
+  pub(crate) category: Option<String>,
+  #[graphql(skip)]
+  pub(crate) category_id: i32,
+  pub(crate) contents: Option<String>,
+  pub(crate) pub_date: DateTime<Utc>,
+  pub(crate) updated_at: DateTime<Utc>,
+  pub(crate) open: i8,
 }
 
 
+// 一覧で投稿ごとにクエリが飛ばないよう、関連は DataLoader でまとめて取得する
+#[ComplexObject]
+impl Post {
+  async fn tags(&self, ctx: &Context<'_>) -> FieldResult<Vec<Tag>> {
+    let loader = ctx.data::<DataLoader<TagsLoader>>()?;
+    let tags = loader.load_one(self.id).await.extend()?;
+    Ok(tags.unwrap_or_default())
+  }
+
+  async fn category_detail(&self, ctx: &Context<'_>) -> FieldResult<Option<Category>> {
+    let loader = ctx.data::<DataLoader<CategoryLoader>>()?;
+    loader.load_one(self.category_id).await.extend()
+  }
+
+  // 一覧では切り詰めた本文をそのまま変換する
+  // 閉じていないコードブロックは末尾までで閉じられ、タグはサニタイズで閉じられる
+  /// contents rendered from Markdown to sanitized HTML, rendered from the excerpt in listings
+  async fn contents_html(&self, ctx: &Context<'_>) -> FieldResult<Option<String>> {
+    let renderer = ctx.data::<Arc<MarkdownRenderer>>()?;
+    let contents = match &self.contents {
+      Some(contents) => contents,
+      None => return Ok(None),
+    };
+    let html = renderer.render(self.id, self.updated_at, contents).await.extend()?;
+    Ok(Some(html.to_string()))
+  }
+}
+
 #[derive(SimpleObject)]
 pub struct Posts {
-  current: i32,
-  next: Option<i32>,
-  prev: Option<i32>,
-  category: String,
-  page_size: i32,
-  results: Vec<Post>,
+  pub(crate) current: i32,
+  pub(crate) next: Option<i32>,
+  pub(crate) prev: Option<i32>,
+  pub(crate) category: String,
+  pub(crate) page_size: i32,
+  pub(crate) results: Vec<Post>,
 }
 
 pub struct QueryRoot;
 
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum BlogError {
     #[error("投稿が存在しません")]
     NotFoundPost,
@@ -56,16 +112,58 @@ pub enum BlogError {
     #[error("投稿が存在しません")]
     NotFoundPosts,
 
+    #[error("カテゴリが存在しません")]
+    NotFoundCategory,
+
+    #[error("認証が必要です")]
+    Unauthorized,
+
+    #[error("不正な引数です: {0}")]
+    InvalidArgument(String),
+
+    #[error("プレビュー用のトークンが不正か、期限切れです")]
+    InvalidPreviewToken,
+
     #[error("ServerError")]
     ServerError(String),
 
 }
 
+impl BlogError {
+  // DB のエラーはクライアントに返す前にログに残す
+  pub fn database(e: sqlx::Error) -> Self {
+    tracing::error!(error = %e, "database error");
+    metrics::record_db_error(&e);
+    BlogError::ServerError(e.to_string())
+  }
+
+  // メトリクスのラベルに使う
+  pub fn kind(&self) -> &'static str {
+    match self {
+      BlogError::NotFoundPost => "NotFoundPost",
+      BlogError::NotFoundPosts => "NotFoundPosts",
+      BlogError::NotFoundCategory => "NotFoundCategory",
+      BlogError::Unauthorized => "Unauthorized",
+      BlogError::InvalidArgument(_) => "InvalidArgument",
+      BlogError::InvalidPreviewToken => "InvalidPreviewToken",
+      BlogError::ServerError(_) => "ServerError",
+    }
+  }
+}
+
 impl ErrorExtensions for BlogError {
   fn extend(&self) -> FieldError {
+      metrics::record_error(self);
       self.extend_with(|err, e| match err {
         BlogError::NotFoundPost => e.set("code", "NOT_FOUND"),
         BlogError::NotFoundPosts => e.set("code", "NOT_FOUND"),
+        BlogError::NotFoundCategory => e.set("code", "NOT_FOUND"),
+        BlogError::Unauthorized => e.set("code", "UNAUTHORIZED"),
+        BlogError::InvalidArgument(reason) => {
+          e.set("code", "BAD_REQUEST");
+          e.set("reason", reason.to_string());
+        },
+        BlogError::InvalidPreviewToken => e.set("code", "INVALID_TOKEN"),
         BlogError::ServerError(reason) => e.set("reason", reason.to_string()),
       })
   }
@@ -76,20 +174,44 @@ impl ErrorExtensions for BlogError {
  */
 #[Object]
 impl QueryRoot {
-  async fn ping(&self) -> Ping {
-    Ping { 
-      status: "ok".to_string(), 
-      code: 200 
-    }
+  // DB に繋がらない場合は status = "degraded", code = 503
+  // 死活監視に使うので、他のフィールドと一緒に頼まれてもキャッシュさせない
+  async fn ping(&self, ctx: &Context<'_>) -> FieldResult<Ping> {
+    NoStore::mark(ctx);
+    let pool = ctx.data::<MySqlPool>()?;
+    let readiness = ctx.data::<Arc<Readiness>>()?;
+    let database = check_database(pool).await;
+    let (status, code) = match database.ok {
+      true => ("ok", 200),
+      false => ("degraded", 503),
+    };
+    Ok(Ping { 
+      status: status.to_string(), 
+      code,
+      database,
+      version: health::VERSION.to_string(),
+      uptime_seconds: readiness.uptime(),
+    })
   }
 
   #[allow(non_snake_case)]
+  #[graphql(cache_control(max_age = 60))]
   async fn getPost(
       &self,
-      _ctx: &Context<'_>,
+      ctx: &Context<'_>,
       #[graphql(desc = "id of the post")] id: i32,
   ) -> FieldResult<Post> {
-    let post = get_post(id).await;
+    let pool = ctx.data::<MySqlPool>()?;
+    let cache = ctx.data::<Arc<ResolverCache>>()?;
+    let post = cache.get_or_fetch(ResolverCache::post_key(id), || get_post(pool, id)).await;
+    // 下書きは管理者以外には存在しないものとして扱う
+    let post = match post {
+      Ok(post) if post.open == 0 && !is_admin(ctx) => Err(BlogError::NotFoundPost),
+      post => post,
+    };
+    if let Err(err) = &post {
+      metrics::record_error(err);
+    }
     match post {
       Ok(post) => Ok(post),
       Err(err) => Err(
@@ -107,19 +229,35 @@ impl QueryRoot {
   }
 
   #[allow(non_snake_case)]
+  #[graphql(complexity = "limits::page_size() * child_complexity", cache_control(max_age = 60))]
   async fn getPosts(
       &self, 
-      _ctx: &Context<'_>,
+      ctx: &Context<'_>,
       #[graphql(desc = "current page")] page: i32, 
-      #[graphql(desc = "selected category")] category: String
+      #[graphql(desc = "selected category")] category: String,
+      #[graphql(desc = "additional conditions")] filter: Option<PostFilter>,
   ) -> FieldResult<Posts> {
+    let pool = ctx.data::<MySqlPool>()?;
+    let config = &ctx.data::<Arc<Config>>()?.posts;
     let page = if page == 0 { 1 } else { page };
     let categoryForResult = category.clone();
-    let count =  match count().await {
+    let mut filter = filter.unwrap_or_default();
+    if filter.category.is_none() {
+      filter.category = PostFilter::category(category).category;
+    }
+    if filter.visibility != Visibility::Published && !is_admin(ctx) {
+      return Err(BlogError::Unauthorized.extend());
+    }
+    let cache = ctx.data::<Arc<ResolverCache>>()?;
+    let count = cache.get_or_fetch(format!("count:{:?}", filter), || count(pool, &filter)).await;
+    if let Err(err) = &count {
+      metrics::record_error(err);
+    }
+    let count =  match count {
       Ok(count) => match count {
         // 0件だったら not found,　
         // fetch_one を実行した場合 count(*) が 0件だったらエラーにならないので手動で not found を設定
-        0 => return Err(BlogError::NotFoundPosts.into()),
+        0 => return Err(BlogError::NotFoundPosts.extend()),
         _ => count,
       },
       Err(err) => return Err(
@@ -132,7 +270,12 @@ impl QueryRoot {
       ),
     };
 
-    let posts = get_posts(page, category).await;
+    let posts = cache
+      .get_or_fetch(format!("posts:{}:{:?}", page, filter), || get_posts(pool, page, &filter, config))
+      .await;
+    if let Err(err) = &posts {
+      metrics::record_error(err);
+    }
     let results = match posts {
       Ok(posts) => posts,
       // 投稿がなかったら　　count　の方で弾かれるので、実質ここのエラーはほぼ呼ばれない
@@ -150,11 +293,10 @@ impl QueryRoot {
       ),
     };
 
-    let page_size = (count / 5) + 1;
+    let page_size = (count / config.page_size) + 1;
     
-    match page > page_size {
-      true => return Err(BlogError::NotFoundPosts.into()),
-      _ => (),
+    if page > page_size {
+      return Err(BlogError::NotFoundPosts.extend());
     }
 
     let next = if page == page_size { Some(page_size) } else { Some(page + 1) };
@@ -170,6 +312,110 @@ impl QueryRoot {
     })
 }
 
+  // トークンを付けずにリクエストした場合は匿名の viewer が返る
+  #[graphql(cache_control(private))]
+  async fn viewer(&self, ctx: &Context<'_>) -> Viewer {
+    NoStore::mark(ctx);
+    match ctx.data_opt::<Viewer>() {
+      Some(viewer) => viewer.clone(),
+      None => Viewer::anonymous(),
+    }
+  }
+
+  // 署名付きトークンを持っていれば、下書きでも読める
+  #[allow(non_snake_case)]
+  #[graphql(cache_control(private))]
+  async fn previewPost(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(desc = "token issued by createPreviewToken")] token: String,
+  ) -> FieldResult<Post> {
+    NoStore::mark(ctx);
+    let pool = ctx.data::<MySqlPool>()?;
+    let secret = match ctx.data_opt::<PreviewSecret>() {
+      Some(secret) => secret,
+      None => return Err(BlogError::InvalidPreviewToken).extend(),
+    };
+    let id = preview::verify(secret, &token).extend()?;
+    get_post(pool, id).await.extend()
+  }
+
+  // Relay の Cursor Connections に沿った一覧
+  #[allow(clippy::too_many_arguments)]
+  #[graphql(complexity = "limits::list_size(first, last) * child_complexity", cache_control(max_age = 60))]
+  async fn posts(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(desc = "number of posts from the start")] first: Option<i32>,
+    #[graphql(desc = "cursor to start after")] after: Option<String>,
+    #[graphql(desc = "number of posts from the end")] last: Option<i32>,
+    #[graphql(desc = "cursor to end before")] before: Option<String>,
+    #[graphql(desc = "selected category")] category: Option<String>,
+    #[graphql(desc = "additional conditions")] filter: Option<PostFilter>,
+  ) -> FieldResult<PostConnection> {
+    let mut filter = filter.unwrap_or_default();
+    if category.is_some() {
+      filter.category = category;
+    }
+    if filter.visibility != Visibility::Published && !is_admin(ctx) {
+      return Err(BlogError::Unauthorized.extend());
+    }
+    pagination::posts(ctx, after, before, first, last, filter).await
+  }
+
+  #[allow(non_snake_case)]
+  #[graphql(complexity = "limits::page_size() * child_complexity", cache_control(max_age = 60))]
+  async fn searchPosts(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(desc = "keywords separated by spaces")] query: String,
+    #[graphql(desc = "current page")] page: i32,
+    #[graphql(desc = "selected category")] category: String,
+  ) -> FieldResult<SearchResults> {
+    let pool = ctx.data::<MySqlPool>()?;
+    let config = &ctx.data::<Arc<Config>>()?.posts;
+    search::search_posts(pool, config, query, page, category).await.extend()
+  }
+
+  // カテゴリやタグは投稿ほど頻繁に変わらない
+  #[graphql(cache_control(max_age = 300))]
+  async fn categories(&self, ctx: &Context<'_>) -> FieldResult<Vec<Category>> {
+    let pool = ctx.data::<MySqlPool>()?;
+    let cache = ctx.data::<Arc<ResolverCache>>()?;
+    cache.get_or_fetch("categories".to_string(), || get_categories(pool)).await.extend()
+  }
+
+  #[graphql(cache_control(max_age = 300))]
+  async fn category(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(desc = "name of the category")] name: String,
+  ) -> FieldResult<Category> {
+    let pool = ctx.data::<MySqlPool>()?;
+    let cache = ctx.data::<Arc<ResolverCache>>()?;
+    cache.get_or_fetch(format!("category:{}", name), || get_category(pool, &name)).await.extend()
+  }
+
+  #[graphql(cache_control(max_age = 300))]
+  async fn tags(&self, ctx: &Context<'_>) -> FieldResult<Vec<Tag>> {
+    let pool = ctx.data::<MySqlPool>()?;
+    get_tags(pool).await.extend()
+  }
+
+  #[allow(non_snake_case)]
+  #[graphql(complexity = "limits::page_size() * child_complexity", cache_control(max_age = 60))]
+  async fn getPostsByTags(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(desc = "current page")] page: i32,
+    #[graphql(desc = "names of the tags")] tags: Vec<String>,
+    #[graphql(desc = "ANY or ALL of the tags", default_with = "TagMatch::Any")] mode: TagMatch,
+  ) -> FieldResult<Posts> {
+    let pool = ctx.data::<MySqlPool>()?;
+    let config = &ctx.data::<Arc<Config>>()?.posts;
+    get_posts_by_tags(pool, config, page, tags, mode).await.extend()
+  }
+
   async fn extend_result(&self) -> FieldResult<Post> {
       Err(BlogError::NotFoundPost).extend()
   }
@@ -186,57 +432,65 @@ impl QueryRoot {
 /**
  * database
  */
-
-async fn pool () -> Result<MySqlPool, BlogError> {
-  let url = match env::var("DATABASE_URL") {
-    Ok(url) => url,
-    Err(_) => {
-      return Err(BlogError::ServerError("DATABASE_URL is not set".to_string()));
-    }
-  };
-  let pool = MySqlPool::connect(&url).await;
+// 起動時に一度だけ呼び出して、Schema の data に登録する
+pub async fn pool(config: &DatabaseConfig) -> Result<MySqlPool, BlogError> {
+  // 実行した SQL は所要時間付きで debug に、遅いものは warn に出す
+  let mut options = MySqlConnectOptions::from_str(&config.url).map_err(BlogError::database)?;
+  options
+    .log_statements(log::LevelFilter::Debug)
+    .log_slow_statements(log::LevelFilter::Warn, Duration::from_millis(config.slow_query_threshold));
+
+  let pool = MySqlPoolOptions::new()
+    .max_connections(config.max_connections)
+    .min_connections(config.min_connections)
+    // sqlx 0.5 の connect_timeout はコネクション取得(acquire)のタイムアウト
+    .connect_timeout(Duration::from_secs(config.acquire_timeout))
+    .idle_timeout(Duration::from_secs(config.idle_timeout))
+    .connect_with(options)
+    .await;
   match pool {
     Ok(pool) => Ok(pool),
-    Err(e) => Err(BlogError::ServerError(e.to_string())),
+    Err(e) => Err(BlogError::database(e)),
   }
 }
 
-// count all posts
-pub async fn count() -> Result<i32, BlogError> {
-  let pool = match pool().await {
-    Ok(pool) => pool,
-    Err(_) => return Err(BlogError::ServerError("Database Error: connection failed".to_string())),
-  };
-
-  let count_all = sqlx::query_as::<_, Count>(
+// count posts matching the filter
+#[instrument(skip_all)]
+pub async fn count(pool: &MySqlPool, filter: &PostFilter) -> Result<i32, BlogError> {
+  let mut query = QueryBuilder::new(
     r#"
-SELECT count(*) as count FROM blogapp_post where open = true
+SELECT count(*) as count
+FROM blogapp_post
+INNER JOIN blogapp_category ON blogapp_post.category_id = blogapp_category.id
+WHERE true
     "#
-)
-  .fetch_one(&pool)
+  );
+  filter.apply(&mut query);
+  let (sql, args) = query.build();
+
+  let count_all = sqlx::query_as_with::<_, Count, _>(sql.as_str(), args)
+  .fetch_one(pool)
   .await;
 
   match count_all {
     Ok(count_all) => Ok(count_all.count as i32),
-    Err(_) => Err(BlogError::ServerError("unknown error".to_string())),
+    Err(e) => Err(BlogError::database(e)),
   }
 }
 
 // get post by id
-pub async fn get_post(id: i32) -> Result<Post, BlogError> {
-  let pool = match pool().await {
-    Ok(pool) => pool,
-    Err(_) => return Err(BlogError::ServerError("Database Error: connection failed".to_string())),
-  };
-
+#[instrument(skip(pool))]
+pub async fn get_post(pool: &MySqlPool, id: i32) -> Result<Post, BlogError> {
   let post = sqlx::query_as::<_, Post>(
     r#"
     SELECT 
       blogapp_post.id as id, 
       title,
       blogapp_category.name as category,
+      blogapp_post.category_id,
       contents, 
       pub_date,
+      blogapp_post.updated_at,
       open
     FROM
       blogapp_post
@@ -249,37 +503,74 @@ pub async fn get_post(id: i32) -> Result<Post, BlogError> {
     "#, 
   )
   .bind(id)
-  .fetch_one(&pool)
+  .fetch_one(pool)
   .await;
   
   match post {
     Ok(post) => Ok(post),
-    Err(_) => Err(BlogError::NotFoundPost),
+    Err(sqlx::Error::RowNotFound) => Err(BlogError::NotFoundPost),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+// get latest posts matching the filter with full contents
+#[instrument(skip(pool, filter))]
+pub async fn get_latest_posts(pool: &MySqlPool, filter: &PostFilter, limit: i32) -> Result<Vec<Post>, BlogError> {
+  let mut query = QueryBuilder::new(
+    "
+    SELECT
+      blogapp_post.id,
+      title,
+      blogapp_category.name as category,
+      blogapp_post.category_id,
+      contents,
+      pub_date,
+      blogapp_post.updated_at,
+      open
+    FROM
+      blogapp_post
+    INNER JOIN
+      blogapp_category
+    ON
+      blogapp_post.category_id = blogapp_category.id
+    WHERE
+      true
+    ",
+  );
+  filter.apply(&mut query);
+  query
+    .push("ORDER BY blogapp_post.pub_date desc LIMIT")
+    .push_bind(limit);
+  let (sql, args) = query.build();
+
+  let posts = sqlx::query_as_with::<_, Post, _>(sql.as_str(), args)
+    .fetch_all(pool)
+    .await;
+
+  match posts {
+    Ok(posts) => Ok(posts),
+    Err(e) => Err(BlogError::database(e)),
   }
 }
 
-// get posts by page and category
-pub async fn get_posts(page: i32, category: String) -> Result<Vec<Post>, BlogError> {
-  let pool = match pool().await {
-    Ok(pool) => pool,
-    Err(_) => return Err(BlogError::ServerError("Database Error: connection failed".to_string())),
-  };
-
-  let offset = if page == 0 { 0 } else { 5 * (page - 1) };
-  let category_query = if category == "" {
-    format!("{}", "")
-  } else {
-    format!("AND blogapp_category.name = '{}'", category)
-  };
-
-  let sql = format!(
+// get posts by page and filter
+#[instrument(skip(pool, filter, config))]
+pub async fn get_posts(pool: &MySqlPool, page: i32, filter: &PostFilter, config: &PostsConfig) -> Result<Vec<Post>, BlogError> {
+  let offset = if page == 0 { 0 } else { config.page_size * (page - 1) };
+
+  let mut query = QueryBuilder::new(
     "
     SELECT 
       blogapp_post.id, 
       title, 
       blogapp_category.name as category, 
-      left(contents, 200) as contents, 
+      blogapp_post.category_id,
+      left(contents,",
+  );
+  query.push_bind(config.excerpt_length).push(
+    ") as contents, 
       pub_date,
+      blogapp_post.updated_at,
       open
     FROM 
       blogapp_post 
@@ -288,27 +579,28 @@ pub async fn get_posts(page: i32, category: String) -> Result<Vec<Post>, BlogErr
     ON
       blogapp_post.category_id = blogapp_category.id
     WHERE 
-      open = true
-      {}
-    ORDER BY
-      blogapp_post.pub_date desc  
-    LIMIT 5
-    OFFSET ?
+      true
     ",
-    category_query
   );
-
-  let posts = sqlx::query_as::<_, Post>(
+  filter.apply(&mut query);
+  query
+    .push("ORDER BY blogapp_post.pub_date desc LIMIT")
+    .push_bind(config.page_size)
+    .push("OFFSET")
+    .push_bind(offset);
+  let (sql, args) = query.build();
+
+  let posts = sqlx::query_as_with::<_, Post, _>(
     sql.as_str(), 
+    args,
   )
-  .bind(offset)
-  .fetch_all(&pool)
+  .fetch_all(pool)
   .await;
 
   match posts {
     Ok(posts) => Ok(posts),
     // fetch_all は該当するレコードがなくてもエラーを吐かない
     // つまりここで拾うべきは想定していない未知のエラー
-    Err(_) => Err(BlogError::ServerError("unknown error".to_string())),
+    Err(e) => Err(BlogError::database(e)),
   }
 }
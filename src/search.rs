@@ -0,0 +1,326 @@
+use chrono::{DateTime, Utc};
+use sqlx::mysql::MySqlPool;
+use tracing::instrument;
+
+use async_graphql::SimpleObject;
+
+use crate::config::PostsConfig;
+use crate::resolvers::{BlogError, Post};
+
+// スニペットはマッチした位置の前後をこの文字数だけ切り出す
+const SNIPPET_BEFORE: usize = 40;
+const SNIPPET_AFTER: usize = 120;
+
+#[derive(SimpleObject)]
+pub struct SearchResult {
+  post: Post,
+  score: f64,
+  /// matched terms are wrapped in <mark>, the rest is HTML-escaped
+  snippet: String,
+}
+
+#[derive(SimpleObject)]
+pub struct SearchResults {
+  current: i32,
+  next: Option<i32>,
+  prev: Option<i32>,
+  query: String,
+  category: String,
+  page_size: i32,
+  total_count: i32,
+  results: Vec<SearchResult>,
+}
+
+#[derive(sqlx::FromRow)]
+struct SearchRow {
+  id: i32,
+  title: String,
+  category: Option<String>,
+  category_id: i32,
+  contents: Option<String>,
+  pub_date: DateTime<Utc>,
+  updated_at: DateTime<Utc>,
+  open: i8,
+  score: f64,
+}
+
+#[derive(sqlx::FromRow)]
+struct Count {
+  count: i64,
+}
+
+// 全角スペースも区切りとして扱う
+fn terms(query: &str) -> Vec<String> {
+  query
+    .split(|c: char| c.is_whitespace() || c == '\u{3000}')
+    .filter(|term| !term.is_empty())
+    .map(|term| term.to_string())
+    .collect()
+}
+
+fn escape_html(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&#39;"),
+      _ => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+// 大文字小文字を区別せずに比較するため、1文字ずつ小文字に寄せる(文字数は変えない)
+fn fold(chars: &[char]) -> Vec<char> {
+  chars.iter().map(|c| c.to_lowercase().next().unwrap_or(*c)).collect()
+}
+
+fn find(haystack: &[char], needle: &[char], from: usize) -> Option<usize> {
+  if needle.is_empty() || haystack.len() < needle.len() {
+    return None;
+  }
+  (from..=haystack.len() - needle.len()).find(|&i| haystack[i..i + needle.len()] == *needle)
+}
+
+// 最初にマッチした箇所の前後を切り出して、マッチした語を <mark> で囲む
+fn snippet(contents: &str, terms: &[String]) -> String {
+  let chars: Vec<char> = contents.chars().collect();
+  let folded = fold(&chars);
+  let needles: Vec<Vec<char>> = terms
+    .iter()
+    .map(|term| fold(&term.chars().collect::<Vec<char>>()))
+    .collect();
+
+  let first = needles.iter().filter_map(|needle| find(&folded, needle, 0)).min();
+  let start = first.map(|i| i.saturating_sub(SNIPPET_BEFORE)).unwrap_or(0);
+  let end = (first.unwrap_or(0) + SNIPPET_AFTER).min(chars.len());
+
+  let mut snippet = String::new();
+  if start > 0 {
+    snippet.push('…');
+  }
+  let mut i = start;
+  while i < end {
+    // 同じ位置で複数の語がマッチしたら長い方を優先する
+    let matched = needles
+      .iter()
+      .filter(|needle| !needle.is_empty() && folded[i..end].starts_with(needle))
+      .map(|needle| needle.len())
+      .max();
+    match matched {
+      Some(len) => {
+        let text: String = chars[i..i + len].iter().collect();
+        snippet.push_str("<mark>");
+        snippet.push_str(&escape_html(&text));
+        snippet.push_str("</mark>");
+        i += len;
+      },
+      None => {
+        snippet.push_str(&escape_html(&chars[i].to_string()));
+        i += 1;
+      },
+    }
+  }
+  if end < chars.len() {
+    snippet.push('…');
+  }
+  snippet
+}
+
+/**
+ * resolvers
+ */
+pub async fn search_posts(
+  pool: &MySqlPool,
+  config: &PostsConfig,
+  query: String,
+  page: i32,
+  category: String,
+) -> Result<SearchResults, BlogError> {
+  let terms = terms(&query);
+  if terms.is_empty() {
+    return Err(BlogError::InvalidArgument("query is empty".to_string()));
+  }
+  let page = if page <= 0 { 1 } else { page };
+
+  let total_count = count_matches(pool, &query, &category).await?;
+  if total_count == 0 {
+    return Err(BlogError::NotFoundPosts);
+  }
+  let page_size = (total_count - 1) / config.page_size + 1;
+  if page > page_size {
+    return Err(BlogError::NotFoundPosts);
+  }
+
+  let results = search(pool, &query, page, config.page_size, &category)
+    .await?
+    .into_iter()
+    .map(|row| {
+      let contents = row.contents.unwrap_or_default();
+      SearchResult {
+        snippet: snippet(&contents, &terms),
+        score: row.score,
+        post: Post {
+          id: row.id,
+          title: row.title,
+          category: row.category,
+          category_id: row.category_id,
+          contents: Some(contents.chars().take(config.excerpt_length as usize).collect()),
+          pub_date: row.pub_date,
+          updated_at: row.updated_at,
+          open: row.open,
+        },
+      }
+    })
+    .collect();
+
+  Ok(SearchResults {
+    current: page,
+    next: if page == page_size { None } else { Some(page + 1) },
+    prev: if page == 1 { None } else { Some(page - 1) },
+    query,
+    category,
+    page_size,
+    total_count,
+    results,
+  })
+}
+
+/**
+ * database
+ */
+// ngram パーサの最小トークン長(既定で 2)より短い語はヒットしない
+const MATCH_AGAINST: &str = "MATCH(title, contents) AGAINST(? IN NATURAL LANGUAGE MODE)";
+
+// count published posts matching the query
+#[instrument(skip_all)]
+async fn count_matches(pool: &MySqlPool, query: &str, category: &str) -> Result<i32, BlogError> {
+  let mut sql = format!(
+    "
+    SELECT count(*) as count
+    FROM blogapp_post
+    INNER JOIN blogapp_category ON blogapp_post.category_id = blogapp_category.id
+    WHERE open = true AND {}
+    ",
+    MATCH_AGAINST
+  );
+  if !category.is_empty() {
+    sql.push_str(" AND blogapp_category.name = ?");
+  }
+
+  let mut count = sqlx::query_as::<_, Count>(sql.as_str()).bind(query);
+  if !category.is_empty() {
+    count = count.bind(category);
+  }
+
+  match count.fetch_one(pool).await {
+    Ok(count) => Ok(count.count as i32),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+// search published posts ordered by relevance
+#[instrument(skip(pool, query, category))]
+async fn search(pool: &MySqlPool, query: &str, page: i32, page_size: i32, category: &str) -> Result<Vec<SearchRow>, BlogError> {
+  let offset = page_size * (page - 1);
+  let mut sql = format!(
+    "
+    SELECT
+      blogapp_post.id,
+      title,
+      blogapp_category.name as category,
+      blogapp_post.category_id,
+      contents,
+      pub_date,
+      blogapp_post.updated_at,
+      open,
+      {match_against} as score
+    FROM
+      blogapp_post
+    INNER JOIN
+      blogapp_category
+    ON
+      blogapp_post.category_id = blogapp_category.id
+    WHERE
+      open = true
+      AND {match_against}
+    ",
+    match_against = MATCH_AGAINST
+  );
+  if !category.is_empty() {
+    sql.push_str(" AND blogapp_category.name = ?");
+  }
+  sql.push_str(" ORDER BY score desc, pub_date desc LIMIT ? OFFSET ?");
+
+  let mut rows = sqlx::query_as::<_, SearchRow>(sql.as_str()).bind(query).bind(query);
+  if !category.is_empty() {
+    rows = rows.bind(category);
+  }
+
+  match rows.bind(page_size).bind(offset).fetch_all(pool).await {
+    Ok(rows) => Ok(rows),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn terms_are_split_on_whitespace_and_ideographic_space() {
+    assert_eq!(terms(" rust\tasync\u{3000}非同期  "), vec!["rust", "async", "非同期"]);
+    assert!(terms(" \u{3000} ").is_empty());
+  }
+
+  #[test]
+  fn marks_matches_case_insensitively() {
+    assert_eq!(snippet("Learning Rust today", &terms("rust")), "Learning <mark>Rust</mark> today");
+  }
+
+  #[test]
+  fn marks_every_term() {
+    assert_eq!(
+      snippet("async in rust and async again", &terms("async rust")),
+      "<mark>async</mark> in <mark>rust</mark> and <mark>async</mark> again",
+    );
+  }
+
+  #[test]
+  fn prefers_longer_terms_at_the_same_position() {
+    assert_eq!(snippet("rustacean", &terms("rust rustacean")), "<mark>rustacean</mark>");
+  }
+
+  #[test]
+  fn escapes_html_inside_and_outside_marks() {
+    assert_eq!(
+      snippet("<b>\"a&b\"</b> 'x'", &terms("a&b")),
+      "&lt;b&gt;&quot;<mark>a&amp;b</mark>&quot;&lt;/b&gt; &#39;x&#39;",
+    );
+    assert_eq!(snippet("<script>", &terms("<script>")), "<mark>&lt;script&gt;</mark>");
+  }
+
+  #[test]
+  fn cuts_around_the_first_match() {
+    let contents = format!("{}match{}", "a".repeat(100), "b".repeat(200));
+    let snippet = snippet(&contents, &terms("match"));
+    let expected = format!("…{}<mark>match</mark>{}…", "a".repeat(SNIPPET_BEFORE), "b".repeat(SNIPPET_AFTER - 5));
+    assert_eq!(snippet, expected);
+  }
+
+  #[test]
+  fn starts_from_the_beginning_without_matches() {
+    let contents = "c".repeat(200);
+    assert_eq!(snippet(&contents, &terms("missing")), format!("{}…", "c".repeat(SNIPPET_AFTER)));
+  }
+
+  #[test]
+  fn counts_characters_not_bytes() {
+    let contents = format!("{}検索{}", "あ".repeat(50), "い".repeat(10));
+    let expected = format!("…{}<mark>検索</mark>{}", "あ".repeat(SNIPPET_BEFORE), "い".repeat(10));
+    assert_eq!(snippet(&contents, &terms("検索")), expected);
+  }
+}
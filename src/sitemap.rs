@@ -0,0 +1,328 @@
+use axum::{
+  extract::{Extension, Path},
+  http::{HeaderMap, StatusCode},
+  response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use sqlx::mysql::MySqlPool;
+use std::sync::Arc;
+use tracing::instrument;
+
+use crate::categories::{get_categories, Category};
+use crate::config::{Config, SiteConfig};
+use crate::feeds::{conditional_response, escape_xml};
+use crate::resolvers::BlogError;
+
+// sitemap の仕様上、1ファイルに載せられる URL の上限
+const MAX_URLS: i64 = 50_000;
+const CONTENT_TYPE: &str = "application/xml; charset=utf-8";
+
+#[derive(sqlx::FromRow)]
+struct PostEntry {
+  id: i32,
+  updated_at: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow)]
+struct Count {
+  count: i64,
+}
+
+struct Url {
+  loc: String,
+  lastmod: Option<DateTime<Utc>>,
+}
+
+fn urlset(urls: &[Url]) -> String {
+  let mut xml = String::new();
+  xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  xml.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+  for url in urls {
+    xml.push_str("<url>");
+    xml.push_str(&format!("<loc>{}</loc>", escape_xml(&url.loc)));
+    if let Some(lastmod) = url.lastmod {
+      xml.push_str(&format!("<lastmod>{}</lastmod>", lastmod.to_rfc3339()));
+    }
+    xml.push_str("</url>\n");
+  }
+  xml.push_str("</urlset>\n");
+  xml
+}
+
+// 子の sitemap は載せている URL と同じホストから配信しないと、sitemap の仕様上受け付けられない
+fn sitemapindex(site: &SiteConfig, files: &[(String, Option<DateTime<Utc>>)]) -> String {
+  let mut xml = String::new();
+  xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  xml.push_str("<sitemapindex xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+  for (file, lastmod) in files {
+    xml.push_str("<sitemap>");
+    xml.push_str(&format!("<loc>{}</loc>", escape_xml(&site.sitemap_url(file))));
+    if let Some(lastmod) = lastmod {
+      xml.push_str(&format!("<lastmod>{}</lastmod>", lastmod.to_rfc3339()));
+    }
+    xml.push_str("</sitemap>\n");
+  }
+  xml.push_str("</sitemapindex>\n");
+  xml
+}
+
+// トップページとカテゴリ一覧ページ
+fn page_urls(site: &SiteConfig, categories: &[Category]) -> Vec<Url> {
+  let mut urls = vec![Url {
+    loc: format!("{}/", site.url),
+    lastmod: categories.iter().filter_map(|category| category.latest_pub_date).max(),
+  }];
+  for category in categories.iter().filter(|category| category.post_count > 0) {
+    urls.push(Url {
+      loc: site.category_url(&category.name),
+      lastmod: category.latest_pub_date,
+    });
+  }
+  urls
+}
+
+fn post_urls(site: &SiteConfig, posts: Vec<PostEntry>) -> Vec<Url> {
+  posts
+    .into_iter()
+    .map(|post| Url {
+      loc: site.post_url(post.id),
+      lastmod: Some(post.updated_at),
+    })
+    .collect()
+}
+
+fn lastmod(urls: &[Url]) -> Option<DateTime<Utc>> {
+  urls.iter().filter_map(|url| url.lastmod).max()
+}
+
+// 上限を超える場合だけ、投稿を何ファイルに分けるかを返す
+fn post_files(page_count: usize, post_count: i64) -> Option<i64> {
+  match page_count as i64 + post_count <= MAX_URLS {
+    true => None,
+    false => Some((post_count - 1) / MAX_URLS + 1),
+  }
+}
+
+/**
+ * handlers
+ */
+pub async fn sitemap_handler(
+  Extension(pool): Extension<MySqlPool>,
+  Extension(config): Extension<Arc<Config>>,
+  headers: HeaderMap,
+) -> Response {
+  let site = &config.site;
+  let result: Result<Response, BlogError> = async {
+    let categories = get_categories(&pool).await?;
+    let pages = page_urls(site, &categories);
+    let post_count = count_published(&pool).await?;
+
+    // 上限に収まる場合は1ファイルで全部返す
+    let post_files = match post_files(pages.len(), post_count) {
+      Some(post_files) => post_files,
+      None => {
+        let mut urls = pages;
+        urls.extend(post_urls(site, list_published(&pool, MAX_URLS, 0).await?));
+        let last_modified = lastmod(&urls);
+        return Ok(conditional_response(&headers, CONTENT_TYPE, urlset(&urls), last_modified));
+      },
+    };
+
+    let latest = latest_update(&pool).await?;
+    let mut files = vec![("pages.xml".to_string(), lastmod(&pages))];
+    for page in 1..=post_files {
+      files.push((format!("posts-{}.xml", page), latest));
+    }
+    Ok(conditional_response(&headers, CONTENT_TYPE, sitemapindex(site, &files), latest))
+  }
+  .await;
+
+  match result {
+    Ok(response) => response,
+    Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+  }
+}
+
+// sitemap index から参照される分割済みのファイル
+pub async fn sitemap_file_handler(
+  Path(file): Path<String>,
+  Extension(pool): Extension<MySqlPool>,
+  Extension(config): Extension<Arc<Config>>,
+  headers: HeaderMap,
+) -> Response {
+  let site = &config.site;
+  let page = match file.as_str() {
+    "pages.xml" => None,
+    _ => match file
+      .strip_prefix("posts-")
+      .and_then(|rest| rest.strip_suffix(".xml"))
+      .and_then(|page| page.parse::<i64>().ok())
+    {
+      Some(page) if page >= 1 => Some(page),
+      _ => return (StatusCode::NOT_FOUND, "not found").into_response(),
+    },
+  };
+
+  let result: Result<Vec<Url>, BlogError> = async {
+    match page {
+      None => Ok(page_urls(site, &get_categories(&pool).await?)),
+      Some(page) => Ok(post_urls(site, list_published(&pool, MAX_URLS, (page - 1) * MAX_URLS).await?)),
+    }
+  }
+  .await;
+
+  match result {
+    Ok(urls) if urls.is_empty() => (StatusCode::NOT_FOUND, "not found").into_response(),
+    Ok(urls) => {
+      let last_modified = lastmod(&urls);
+      conditional_response(&headers, CONTENT_TYPE, urlset(&urls), last_modified)
+    },
+    Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+  }
+}
+
+/**
+ * database
+ */
+// count published posts
+#[instrument(skip_all)]
+async fn count_published(pool: &MySqlPool) -> Result<i64, BlogError> {
+  let count = sqlx::query_as::<_, Count>("SELECT count(*) as count FROM blogapp_post WHERE open = true")
+    .fetch_one(pool)
+    .await;
+
+  match count {
+    Ok(count) => Ok(count.count),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+// get latest updated_at of published posts
+#[instrument(skip_all)]
+async fn latest_update(pool: &MySqlPool) -> Result<Option<DateTime<Utc>>, BlogError> {
+  let latest = sqlx::query_as::<_, (Option<DateTime<Utc>>,)>(
+    "SELECT max(updated_at) FROM blogapp_post WHERE open = true",
+  )
+  .fetch_one(pool)
+  .await;
+
+  match latest {
+    Ok((latest,)) => Ok(latest),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+// list ids of published posts
+#[instrument(skip(pool))]
+async fn list_published(pool: &MySqlPool, limit: i64, offset: i64) -> Result<Vec<PostEntry>, BlogError> {
+  let posts = sqlx::query_as::<_, PostEntry>(
+    "
+    SELECT id, updated_at
+    FROM blogapp_post
+    WHERE open = true
+    ORDER BY id
+    LIMIT ?
+    OFFSET ?
+    ",
+  )
+  .bind(limit)
+  .bind(offset)
+  .fetch_all(pool)
+  .await;
+
+  match posts {
+    Ok(posts) => Ok(posts),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn date(s: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+  }
+
+  fn category(name: &str, post_count: i64, latest_pub_date: Option<&str>) -> Category {
+    Category {
+      id: 1,
+      name: name.to_string(),
+      post_count,
+      latest_pub_date: latest_pub_date.map(date),
+    }
+  }
+
+  #[test]
+  fn urlset_xml() {
+    let urls = vec![
+      Url { loc: "https://takurinton.dev/?a=1&b=2".to_string(), lastmod: Some(date("2022-01-01T00:00:00Z")) },
+      Url { loc: "https://takurinton.dev/post/1".to_string(), lastmod: None },
+    ];
+    assert_eq!(
+      urlset(&urls),
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+       <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n\
+       <url><loc>https://takurinton.dev/?a=1&amp;b=2</loc><lastmod>2022-01-01T00:00:00+00:00</lastmod></url>\n\
+       <url><loc>https://takurinton.dev/post/1</loc></url>\n\
+       </urlset>\n"
+    );
+  }
+
+  #[test]
+  fn sitemapindex_xml() {
+    let site = SiteConfig {
+      sitemap_url: Some("https://sitemaps.takurinton.dev".to_string()),
+      ..SiteConfig::default()
+    };
+    let files = vec![
+      ("pages.xml".to_string(), None),
+      ("posts-1.xml".to_string(), Some(date("2022-01-01T00:00:00Z"))),
+    ];
+    assert_eq!(
+      sitemapindex(&site, &files),
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+       <sitemapindex xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n\
+       <sitemap><loc>https://sitemaps.takurinton.dev/sitemaps/pages.xml</loc></sitemap>\n\
+       <sitemap><loc>https://sitemaps.takurinton.dev/sitemaps/posts-1.xml</loc><lastmod>2022-01-01T00:00:00+00:00</lastmod></sitemap>\n\
+       </sitemapindex>\n"
+    );
+  }
+
+  #[test]
+  fn page_urls_skip_empty_categories() {
+    let site = SiteConfig::default();
+    let categories = vec![
+      category("rust", 2, Some("2022-02-01T00:00:00Z")),
+      category("empty", 0, None),
+      category("日本語", 1, Some("2022-01-01T00:00:00Z")),
+    ];
+    let urls = page_urls(&site, &categories);
+    let locs: Vec<&str> = urls.iter().map(|url| url.loc.as_str()).collect();
+    assert_eq!(
+      locs,
+      vec![
+        "https://takurinton.dev/",
+        "https://takurinton.dev/category/rust",
+        "https://takurinton.dev/category/%E6%97%A5%E6%9C%AC%E8%AA%9E",
+      ]
+    );
+    // トップページは一番新しいカテゴリの日時
+    assert_eq!(urls[0].lastmod, Some(date("2022-02-01T00:00:00Z")));
+  }
+
+  #[test]
+  fn fits_in_one_file() {
+    assert_eq!(post_files(1, 0), None);
+    assert_eq!(post_files(10, MAX_URLS - 10), None);
+  }
+
+  #[test]
+  fn splits_posts_by_max_urls() {
+    assert_eq!(post_files(10, MAX_URLS - 9), Some(1));
+    assert_eq!(post_files(1, MAX_URLS), Some(1));
+    assert_eq!(post_files(1, MAX_URLS + 1), Some(2));
+    assert_eq!(post_files(1, MAX_URLS * 2), Some(2));
+    assert_eq!(post_files(1, MAX_URLS * 2 + 1), Some(3));
+  }
+}
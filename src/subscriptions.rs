@@ -0,0 +1,160 @@
+use futures_util::{stream, Stream, StreamExt};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+
+use async_graphql::{
+  extensions::{
+    Extension,
+    ExtensionContext,
+    ExtensionFactory,
+    NextParseQuery,
+  },
+  parser::types::{ExecutableDocument, OperationType},
+  Context,
+  ErrorExtensionValues,
+  FieldResult,
+  ServerError,
+  ServerResult,
+  Subscription,
+  Variables,
+};
+
+use crate::resolvers::Post;
+
+// 購読者がいなくても送信側は詰まらないので、溢れた分は古いものから捨てられる
+const CHANNEL_CAPACITY: usize = 64;
+
+#[derive(Clone)]
+pub enum PostEvent {
+  Published(Post),
+  Updated(Post),
+  // 削除されたか非公開になった投稿。非公開にした場合も変更前の内容を流す
+  Deleted(Post),
+}
+
+// 投稿の変更を subscription に流すためのプロセス内チャンネル
+#[derive(Clone)]
+pub struct PostEvents(broadcast::Sender<PostEvent>);
+
+impl PostEvents {
+  pub fn new() -> Self {
+    let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+    PostEvents(sender)
+  }
+
+  pub fn send(&self, event: PostEvent) {
+    // 購読者が 0 の時は Err になるが、それは正常なので無視する
+    let _ = self.0.send(event);
+  }
+
+  fn subscribe(&self) -> impl Stream<Item = PostEvent> {
+    // 受信が遅れて取りこぼした場合(Lagged)は読み飛ばす
+    BroadcastStream::new(self.0.subscribe()).filter_map(|event| async move { event.ok() })
+  }
+}
+
+impl Default for PostEvents {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+pub struct SubscriptionRoot;
+
+/**
+ * subscriptions
+ */
+#[Subscription]
+impl SubscriptionRoot {
+  #[allow(non_snake_case)]
+  async fn postPublished(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(desc = "selected category, all categories if omitted")] category: Option<String>,
+  ) -> FieldResult<impl Stream<Item = Post>> {
+    let events = ctx.data::<PostEvents>()?;
+    // 以前のクライアントが送ってくる空文字も全カテゴリとして扱う
+    let category = category.filter(|category| !category.is_empty());
+    Ok(events.subscribe().filter_map(move |event| {
+      let post = match event {
+        PostEvent::Published(post) if category.is_none() || post.category == category => Some(post),
+        _ => None,
+      };
+      async move { post }
+    }))
+  }
+
+  // 投稿が削除されたか非公開になった場合は変更前の投稿を流して、購読を終了する
+  #[allow(non_snake_case)]
+  async fn postUpdated(
+    &self,
+    ctx: &Context<'_>,
+    #[graphql(desc = "id of the post")] id: i32,
+  ) -> FieldResult<impl Stream<Item = Post>> {
+    let events = Box::pin(ctx.data::<PostEvents>()?.subscribe());
+    Ok(stream::unfold(Some(events), move |events| async move {
+      let mut events = events?;
+      while let Some(event) = events.next().await {
+        match event {
+          PostEvent::Updated(post) if post.id == id => return Some((post, Some(events))),
+          PostEvent::Deleted(post) if post.id == id => return Some((post, None)),
+          _ => (),
+        }
+      }
+      None
+    }))
+  }
+}
+
+/**
+ * extensions
+ */
+// /ws の接続に載せる印
+pub struct WebSocketRequest;
+
+fn subscription_required(operation_type: OperationType) -> ServerError {
+  let mut extensions = ErrorExtensionValues::default();
+  extensions.set("code", "METHOD_NOT_ALLOWED");
+  let mut error = ServerError::new(format!("{} operations must be sent over HTTP", operation_type), None);
+  error.extensions = Some(extensions);
+  error
+}
+
+// graphql-ws では query や mutation も送れるが、HTTP のハンドラを通らないので /ws では subscription だけを実行する
+pub struct SubscriptionOnly;
+
+impl ExtensionFactory for SubscriptionOnly {
+  fn create(&self) -> Arc<dyn Extension> {
+    Arc::new(SubscriptionOnlyExtension)
+  }
+}
+
+struct SubscriptionOnlyExtension;
+
+#[async_trait::async_trait]
+impl Extension for SubscriptionOnlyExtension {
+  async fn parse_query(
+    &self,
+    ctx: &ExtensionContext<'_>,
+    query: &str,
+    variables: &Variables,
+    next: NextParseQuery<'_>,
+  ) -> ServerResult<ExecutableDocument> {
+    let document = next.run(ctx, query, variables).await?;
+    if ctx.data_opt::<WebSocketRequest>().is_none() {
+      return Ok(document);
+    }
+
+    // 接続ごとのデータには operationName がないので、document の全ての operation を見る
+    let operation = document
+      .operations
+      .iter()
+      .map(|(_, operation)| operation.node.ty)
+      .find(|ty| *ty != OperationType::Subscription);
+    match operation {
+      Some(ty) => Err(subscription_required(ty)),
+      None => Ok(document),
+    }
+  }
+}
@@ -0,0 +1,191 @@
+use sqlx::{
+  mysql::{MySql, MySqlPool},
+  Transaction,
+};
+use std::collections::HashMap;
+use tracing::instrument;
+
+use async_graphql::{
+  Enum,
+  SimpleObject,
+};
+
+use crate::config::PostsConfig;
+use crate::filter::{PostFilter, QueryBuilder};
+use crate::resolvers::{count, get_posts, BlogError, Posts};
+
+#[derive(Clone, SimpleObject)]
+#[derive(sqlx::FromRow)]
+pub struct Tag {
+  id: i32,
+  name: String,
+  /// number of published posts with the tag
+  post_count: i64,
+}
+
+// 複数タグ指定時の絞り込み方
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum TagMatch {
+  /// posts with at least one of the tags
+  #[default]
+  Any,
+  /// posts with every tag
+  All,
+}
+
+#[derive(sqlx::FromRow)]
+struct PostTag {
+  post_id: i32,
+  id: i32,
+  name: String,
+  post_count: i64,
+}
+
+// 前後の空白を除いて小文字に揃え、空のものと重複を取り除く
+// "Rust" と "rust" を別々に数えると ALL の件数が合わなくなる
+pub fn normalize(names: Vec<String>) -> Vec<String> {
+  let mut normalized: Vec<String> = Vec::new();
+  for name in names {
+    let name = name.trim().to_lowercase();
+    if !name.is_empty() && !normalized.contains(&name) {
+      normalized.push(name);
+    }
+  }
+  normalized
+}
+
+/**
+ * resolvers
+ */
+pub async fn get_posts_by_tags(
+  pool: &MySqlPool,
+  config: &PostsConfig,
+  page: i32,
+  tags: Vec<String>,
+  mode: TagMatch,
+) -> Result<Posts, BlogError> {
+  let tags = normalize(tags);
+  if tags.is_empty() {
+    return Err(BlogError::InvalidArgument("tags is empty".to_string()));
+  }
+  let page = if page <= 0 { 1 } else { page };
+  let filter = PostFilter {
+    tags: Some(tags),
+    tag_match: mode,
+    ..PostFilter::default()
+  };
+
+  let count = count(pool, &filter).await?;
+  if count == 0 {
+    return Err(BlogError::NotFoundPosts);
+  }
+  let page_size = (count - 1) / config.page_size + 1;
+  if page > page_size {
+    return Err(BlogError::NotFoundPosts);
+  }
+
+  let results = get_posts(pool, page, &filter, config).await?;
+
+  Ok(Posts {
+    current: page,
+    next: if page == page_size { Some(page_size) } else { Some(page + 1) },
+    prev: Some(page - 1),
+    category: "".to_string(),
+    page_size,
+    results,
+  })
+}
+
+/**
+ * database
+ */
+const TAG_POST_COUNT: &str = "
+  (
+    SELECT count(*)
+    FROM blogapp_post_tags AS counted
+    INNER JOIN blogapp_post ON counted.post_id = blogapp_post.id
+    WHERE counted.tag_id = blogapp_tag.id AND blogapp_post.open = true
+  )
+";
+
+// get all tags with usage counts
+#[instrument(skip_all)]
+pub async fn get_tags(pool: &MySqlPool) -> Result<Vec<Tag>, BlogError> {
+  let sql = format!(
+    "
+    SELECT id, name, {} as post_count
+    FROM blogapp_tag
+    ORDER BY post_count desc, name
+    ",
+    TAG_POST_COUNT
+  );
+
+  match sqlx::query_as::<_, Tag>(sql.as_str()).fetch_all(pool).await {
+    Ok(tags) => Ok(tags),
+    Err(e) => Err(BlogError::database(e)),
+  }
+}
+
+// get tags of the posts
+#[instrument(skip(pool))]
+pub async fn get_tags_by_post_ids(pool: &MySqlPool, post_ids: &[i32]) -> Result<HashMap<i32, Vec<Tag>>, BlogError> {
+  let mut query = QueryBuilder::new(&format!(
+    "
+    SELECT blogapp_post_tags.post_id, blogapp_tag.id, blogapp_tag.name, {} as post_count
+    FROM blogapp_tag
+    INNER JOIN blogapp_post_tags ON blogapp_post_tags.tag_id = blogapp_tag.id
+    WHERE blogapp_post_tags.post_id IN (",
+    TAG_POST_COUNT
+  ));
+  query.push_bind_list(post_ids.to_vec()).push(") ORDER BY blogapp_tag.name");
+  let (sql, args) = query.build();
+
+  let rows = match sqlx::query_as_with::<_, PostTag, _>(sql.as_str(), args).fetch_all(pool).await {
+    Ok(rows) => rows,
+    Err(e) => return Err(BlogError::database(e)),
+  };
+
+  let mut tags: HashMap<i32, Vec<Tag>> = HashMap::new();
+  for row in rows {
+    tags.entry(row.post_id).or_default().push(Tag {
+      id: row.id,
+      name: row.name,
+      post_count: row.post_count,
+    });
+  }
+  Ok(tags)
+}
+
+// replace tags of the post, creating tags that do not exist yet
+// 投稿の INSERT / UPDATE と同じトランザクションで実行し、途中で失敗した場合はまとめて戻す
+#[instrument(skip(tx, names), fields(tags = names.len()))]
+pub async fn set_post_tags(tx: &mut Transaction<'_, MySql>, post_id: i32, names: Vec<String>) -> Result<(), BlogError> {
+  let names = normalize(names);
+
+  let result: Result<(), sqlx::Error> = async {
+    sqlx::query("DELETE FROM blogapp_post_tags WHERE post_id = ?")
+      .bind(post_id)
+      .execute(&mut *tx)
+      .await?;
+    for name in &names {
+      sqlx::query("INSERT IGNORE INTO blogapp_tag (name) VALUES (?)")
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+      sqlx::query(
+        "
+        INSERT INTO blogapp_post_tags (post_id, tag_id)
+        SELECT ?, id FROM blogapp_tag WHERE name = ?
+        ",
+      )
+      .bind(post_id)
+      .bind(name)
+      .execute(&mut *tx)
+      .await?;
+    }
+    Ok(())
+  }
+  .await;
+
+  result.map_err(BlogError::database)
+}
@@ -0,0 +1,36 @@
+use axum::http::Request;
+use tracing::Span;
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+
+use crate::config::{LogConfig, LogFormat};
+
+// 受け取ったものがあればそのまま使い、なければ生成して付け直す
+pub const REQUEST_ID: &str = "x-request-id";
+
+// span が閉じた時に所要時間 (time.busy / time.idle) を出す
+pub fn init(config: &LogConfig) {
+  // log.level は Config::validate で確認済み
+  let builder = tracing_subscriber::fmt()
+    .with_env_filter(EnvFilter::new(&config.level))
+    .with_span_events(FmtSpan::CLOSE);
+  match config.format {
+    LogFormat::Pretty => builder.pretty().init(),
+    LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).init(),
+  }
+}
+
+// HTTP リクエストごとの span。GraphQL や SQL の span はこの下にぶら下がる
+// クエリ文字列には検索語や変数、プレビュー用トークンが入るので、パスだけ記録する
+pub fn make_span<B>(req: &Request<B>) -> Span {
+  let request_id = req
+    .headers()
+    .get(REQUEST_ID)
+    .and_then(|value| value.to_str().ok())
+    .unwrap_or_default();
+  tracing::info_span!(
+    "request",
+    request_id,
+    method = %req.method(),
+    path = %req.uri().path(),
+  )
+}